@@ -0,0 +1,231 @@
+//! the access log, one json line for every session of sslocal or ssserver:
+//!
+//!   {"start": "2018-06-01T08:30:00.250Z", "end": "2018-06-01T08:30:02.000Z", "duration_ms": 1750,
+//!    "client": "127.0.0.1:50312", "user": "alice", "host": "example.com", "ip": "93.184.216.34",
+//!    "port": 443, "decision": "allow", "upstream": null, "up": 517, "down": 5120,
+//!    "reason": "client_closed", "error": null}
+//!
+//! decision is proxy, direct or reject on sslocal and allow or deny on ssserver.
+//! reason is client_closed or target_closed for an eof, client_error or
+//! target_error for a failed read or write, timeout, handshake_failed or connect_failed.
+//! the file is opened again on every reload, so it can be rotated.
+
+use define::ErrCode;
+use define::ErrCode::*;
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{ErrorKind, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+
+use serde_json;
+use serde_json::Value;
+
+use helper;
+
+#[derive(Debug, Clone)]
+pub struct AccessLog {
+    file: Arc<Mutex<Option<File>>>,
+}
+
+impl AccessLog {
+
+    pub fn new() -> Self {
+        AccessLog {
+            file: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    ///none stops the log
+    pub fn set_path(&self, path:Option<&str>) -> Result<(), ErrCode> {
+        let file = match path {
+            Some(path) => Some(OpenOptions::new().create(true).append(true).open(path).or_else(|e| {
+                error!("open the access log {} failed, {}", path, e);
+                Err(FileErr)
+            })?),
+            None => None,
+        };
+        *self.file.lock().or(Err(LockErr))? = file;
+        Ok(())
+    }
+
+    pub fn open(&self, client:Option<SocketAddr>, user:Option<&str>) -> Session {
+        Session {
+            log: self.clone(),
+            start: SystemTime::now(),
+            began: Instant::now(),
+            client: client,
+            user: user.map(|user| user.to_string()),
+            host: None,
+            ip: None,
+            port: None,
+            decision: None,
+            upstream: None,
+            reason: None,
+            error: None,
+        }
+    }
+
+    fn write(&self, line:&str) {
+        if let Ok(mut file) = self.file.lock() {
+            if let Some(ref mut file) = *file {
+                let _ = file.write_all(format!("{}\n", line).as_bytes());
+            }
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.file.lock().map(|file| file.is_some()).unwrap_or(false)
+    }
+}
+
+///the reason of a failed read or write, error names the side
+pub fn io_reason(e:&io::Error, error:&'static str) -> &'static str {
+    match e.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => "timeout",
+        _ => error,
+    }
+}
+
+///the record of one session, written by finish
+#[derive(Debug)]
+pub struct Session {
+    log: AccessLog,
+    start: SystemTime,
+    began: Instant,
+    client: Option<SocketAddr>,
+    user: Option<String>,
+    host: Option<String>,
+    ip: Option<IpAddr>,
+    port: Option<u16>,
+    decision: Option<&'static str>,
+    upstream: Option<String>,
+    reason: Option<&'static str>,
+    error: Option<String>,
+}
+
+impl Session {
+
+    pub fn set_target(&mut self, host:Option<&str>, ip:Option<IpAddr>, port:u16) {
+        self.host = host.map(|host| host.to_string());
+        self.ip = ip;
+        self.port = Some(port);
+    }
+
+    ///the address the target resolved to
+    pub fn set_ip(&mut self, ip:IpAddr) {
+        self.ip = Some(ip);
+    }
+
+    pub fn set_decision(&mut self, decision:&'static str) {
+        self.decision = Some(decision);
+    }
+
+    pub fn set_upstream(&mut self, upstream:&str) {
+        self.upstream = Some(upstream.to_string());
+    }
+
+    ///the first reason is kept, the error is the ErrCode or the socks5 reply
+    pub fn end(&mut self, reason:&'static str, error:Option<String>) {
+        if self.reason.is_none() {
+            self.reason = Some(reason);
+            self.error = error;
+        }
+    }
+
+    pub fn finish(&self, up:u64, down:u64) {
+        if !self.log.enabled() {
+            return;
+        }
+        let elapsed = self.began.elapsed();
+        let text = |v:Option<String>| v.map(Value::String).unwrap_or(Value::Null);
+        let mut record = serde_json::Map::new();
+        let _ = record.insert("start".to_string(), Value::String(helper::rfc3339(self.start)));
+        let _ = record.insert("end".to_string(), Value::String(helper::rfc3339(SystemTime::now())));
+        let _ = record.insert("duration_ms".to_string(), Value::from(elapsed.as_secs() * 1000 + elapsed.subsec_millis() as u64));
+        let _ = record.insert("client".to_string(), text(self.client.map(|addr| addr.to_string())));
+        let _ = record.insert("user".to_string(), text(self.user.clone()));
+        let _ = record.insert("host".to_string(), text(self.host.clone()));
+        let _ = record.insert("ip".to_string(), text(self.ip.map(|ip| ip.to_string())));
+        let _ = record.insert("port".to_string(), self.port.map(Value::from).unwrap_or(Value::Null));
+        let _ = record.insert("decision".to_string(), text(self.decision.map(|d| d.to_string())));
+        let _ = record.insert("upstream".to_string(), text(self.upstream.clone()));
+        let _ = record.insert("up".to_string(), Value::from(up));
+        let _ = record.insert("down".to_string(), Value::from(down));
+        let _ = record.insert("reason".to_string(), Value::String(self.reason.unwrap_or("closed").to_string()));
+        let _ = record.insert("error".to_string(), text(self.error.clone()));
+        self.log.write(&Value::Object(record).to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    fn records(path:&str) -> Vec<Value> {
+        fs::read_to_string(path).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+    }
+
+    #[test]
+    fn finish_writes_one_line() {
+        let path = env::temp_dir().join(format!("ss_rust_access_{}.log", process::id())).to_string_lossy().into_owned();
+        let _ = fs::remove_file(&path);
+        let log = AccessLog::new();
+        //nothing is written without a path
+        log.open(None, None).finish(1, 1);
+        log.set_path(Some(&path)).unwrap();
+
+        let mut session = log.open(Some("127.0.0.1:50312".parse().unwrap()), Some("alice"));
+        session.set_target(Some("example.com"), None, 443);
+        session.set_ip("93.184.216.34".parse().unwrap());
+        session.set_decision("allow");
+        session.end("client_closed", None);
+        session.end("target_error", Some("SocketErr".to_string()));
+        session.finish(517, 5120);
+        let mut session = log.open(None, None);
+        session.end("connect_failed", Some("HostUnreachable".to_string()));
+        session.finish(0, 0);
+        log.open(None, None).finish(0, 0);
+        log.set_path(None).unwrap();
+        log.open(None, None).finish(0, 0);
+
+        let records = records(&path);
+        assert_eq!(records.len(), 3);
+        let record = records[0].as_object().unwrap();
+        let mut keys:Vec<&str> = record.keys().map(|key| key.as_str()).collect();
+        keys.sort();
+        assert_eq!(keys, vec!["client", "decision", "down", "duration_ms", "end", "error", "host", "ip", "port", "reason", "start", "up", "upstream", "user"]);
+        assert_eq!(record["client"], "127.0.0.1:50312");
+        assert_eq!(record["user"], "alice");
+        assert_eq!(record["host"], "example.com");
+        assert_eq!(record["ip"], "93.184.216.34");
+        assert_eq!(record["port"], 443);
+        assert_eq!(record["decision"], "allow");
+        assert_eq!(record["upstream"], Value::Null);
+        assert_eq!((record["up"].as_u64(), record["down"].as_u64()), (Some(517), Some(5120)));
+        //the first reason wins
+        assert_eq!(record["reason"], "client_closed");
+        assert_eq!(record["error"], Value::Null);
+        assert!(record["duration_ms"].is_u64());
+        assert!(record["start"].as_str().unwrap().ends_with('Z') && record["end"].is_string());
+
+        assert_eq!((&records[1]["reason"], &records[1]["error"]), (&Value::from("connect_failed"), &Value::from("HostUnreachable")));
+        assert_eq!((&records[1]["client"], &records[1]["port"]), (&Value::Null, &Value::Null));
+        assert_eq!(records[2]["reason"], "closed");
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn reasons_of_io_errors() {
+        let error = |kind:ErrorKind| io::Error::new(kind, "test");
+        assert_eq!(io_reason(&error(ErrorKind::WouldBlock), "client_error"), "timeout");
+        assert_eq!(io_reason(&error(ErrorKind::TimedOut), "target_error"), "timeout");
+        assert_eq!(io_reason(&error(ErrorKind::ConnectionReset), "client_error"), "client_error");
+        assert_eq!(io_reason(&error(ErrorKind::BrokenPipe), "target_error"), "target_error");
+    }
+}
@@ -0,0 +1,93 @@
+//! admin interface, one command per line over a local tcp socket.
+//! the reply is the text returned by the command, ended with an empty line.
+
+use define::ErrCode;
+use define::ErrCode::*;
+
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+
+use signal;
+
+///the handler gets the arguments after the command name
+pub type Command = Box<dyn Fn(&str) -> String + Send + Sync>;
+
+pub struct Admin {
+    ip: String,
+    port: u32,
+    listener: TcpListener,
+    commands: BTreeMap<String, Command>,
+}
+
+impl Admin {
+
+    pub fn new(ip:&str, port:u32) -> Result<Self, ErrCode> {
+        let url = format!("{}:{}", ip, port);
+        let listener = TcpListener::bind(&url).or_else(|e|{
+            error!("{}", e);
+            Err(UrlErr)
+        })?;
+        let mut admin = Admin {
+            ip: ip.to_string(),
+            port: port,
+            listener: listener,
+            commands: BTreeMap::new(),
+        };
+        admin.register("reload", |_| {
+            signal::reload();
+            "ok".to_string()
+        });
+        Ok(admin)
+    }
+
+    pub fn register<F>(&mut self, name:&str, command:F) where F: Fn(&str) -> String + Send + Sync + 'static {
+        self.commands.insert(name.to_string(), Box::new(command));
+    }
+
+    ///serve the commands in the background
+    pub fn start(self) {
+        info!("admin start listening on {}:{}", self.ip, self.port);
+        let commands = Arc::new(self.commands);
+        let listener = self.listener;
+        let _ = thread::spawn(move || {
+            for stream_rst in listener.incoming() {
+                if let Ok(stream) = stream_rst {
+                    let commands = commands.clone();
+                    let _ = thread::spawn(move || {
+                        let _ = Self::handle_stream(stream, commands);
+                    });
+                }
+            }
+        });
+    }
+
+    fn handle_stream(stream:TcpStream, commands:Arc<BTreeMap<String, Command>>) -> Result<(), ErrCode> {
+        let mut writer = stream.try_clone().or(Err(SocketErr))?;
+        let reader = BufReader::new(stream);
+        for line_rst in reader.lines() {
+            let line = line_rst.or(Err(SocketErr))?;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut parts = line.splitn(2, ' ');
+            let name = parts.next().unwrap_or("");
+            let args = parts.next().unwrap_or("").trim();
+            let back = match commands.get(name) {
+                Some(command) => {
+                    info!("admin command: {}", line);
+                    command(args)
+                },
+                None => {
+                    let names:Vec<&str> = commands.keys().map(|k| k.as_str()).collect();
+                    format!("unknown command {}, try: {}", name, names.join(" "))
+                },
+            };
+            let _ = writer.write_all(format!("{}\n\n", back.trim_end()).as_bytes()).or(Err(SocketErr))?;
+        }
+        Ok(())
+    }
+}
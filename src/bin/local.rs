@@ -2,30 +2,158 @@ extern crate ss_rust;
 #[macro_use]
 extern crate log;
 
-extern crate base_config;
-use base_config::CFG;
-
 extern crate base_log;
 use base_log::init_base_log;
 
-use ss_rust::{ErrCode, local};
+use ss_rust::{ErrCode, local, signal, sip002};
+use ss_rust::admin::Admin;
+use ss_rust::config::{Config, Role, Mode};
+use ss_rust::cli;
+use ss_rust::cli::{Action, Options};
+use ss_rust::signal::Event;
+use ss_rust::local::{pac, rule};
 use ErrCode::*;
 
-fn try_main() -> Result<(), ErrCode> {
-    info!("{}", *CFG);
-    let local_addr = CFG["local_address"].as_str().ok_or(KeyFmtErr)?;
-    let local_port = CFG["local_port"].as_u64().ok_or(KeyFmtErr)? as u32;
+use std::env;
+use std::process;
+use std::time::Duration;
+
+///read the config, every validation error is reported
+fn load(options:&Options) -> Result<Config, ErrCode> {
+    options.load(Role::Local).or_else(|errors| {
+        for e in errors {
+            error!("config error, {}", e);
+            eprintln!("config error, {}", e);
+        }
+        Err(ConfigErr)
+    })
+}
+
+///the values which don't need a rebind apply to the new connections
+fn apply(server:&mut local::LocalServer, checker:&local::HealthChecker, pac:&local::Pac, watcher:&local::Watcher, cfg:&Config) -> Result<(), ErrCode> {
+    //watch the files first, a fixed file reloads again after an error
+    let files:Vec<String> = cfg.rules.iter().chain(cfg.gfwlist.iter()).cloned().collect();
+    let _ = watcher.set_files(&files)?;
+    let rules = match cfg.rules {
+        Some(ref path) => rule::load(path).or_else(|errors| {
+            for e in errors {
+                error!("rule error, {}", e);
+                eprintln!("rule error, {}", e);
+            }
+            Err(ConfigErr)
+        })?,
+        None => Vec::new(),
+    };
+    let gfwlist = match cfg.gfwlist {
+        Some(ref path) => Some(local::GfwList::load(path).or_else(|errors| {
+            for e in errors {
+                error!("gfwlist error, {}", e);
+                eprintln!("gfwlist error, {}", e);
+            }
+            Err(ConfigErr)
+        })?),
+        None => None,
+    };
+    let _ = server.rebind(&cfg.local_address, cfg.local_port)?;
+    let _ = match cfg.pac_port {
+        Some(pac_port) => pac.serve(&cfg.pac_address, pac_port)?,
+        None => pac.stop()?,
+    };
+    info!("{} routing rules", rules.len());
+    let _ = pac.set(pac::generate(&rules, gfwlist.as_ref()), &cfg.local_address, cfg.local_port)?;
+    let _ = server.router().set_rules(rules)?;
+    let _ = server.router().set_gfwlist(gfwlist)?;
+    let _ = server.balancer().set_servers(&cfg.servers, cfg.strategy)?;
+    let _ = checker.set_config(cfg.health_check.clone(), cfg.connect_status)?;
+    server.set_time_out(cfg.timeout);
+    server.set_connect_status(cfg.connect_status);
+    server.set_drain_time_out(cfg.drain_timeout);
+    let _ = server.set_access_log(cfg.access_log.as_ref().map(|path| path.as_str()))?;
+    server.set_limits(cfg.limits);
+    Ok(())
+}
+
+fn start_admin(cfg:&Config, checker:&local::HealthChecker) -> Result<(), ErrCode> {
+    if let Some(admin_port) = cfg.admin_port {
+        let mut admin = Admin::new(&cfg.admin_address, admin_port)?;
+        let checker = checker.clone();
+        admin.register("health", move |_| checker.table());
+        admin.start();
+    }
+    Ok(())
+}
+
+fn try_main(options:Options) -> Result<(), ErrCode> {
+    let cfg = load(&options)?;
+    info!("{:?}", cfg);
+    if options.print_url {
+        for server in cfg.servers.iter() {
+            println!("{}", sip002::generate(server));
+        }
+        return Ok(());
+    }
+    if cfg.mode != Mode::TcpOnly {
+        warn!("udp relay is not supported, mode {} works as tcp_only", cfg.mode.as_str());
+    }
 
-    let server = CFG["server"].as_str().ok_or(KeyFmtErr)?;
-    let server_port = CFG["server_port"].as_u64().ok_or(KeyFmtErr)? as u32;
+    for remote in cfg.servers.iter() {
+        if remote.plugin.is_some() {
+            warn!("plugins are not supported, {:?} of {} is ignored", remote.plugin, remote.server);
+        }
+    }
 
-    let time_out = CFG["timeout"].as_u64().ok_or(KeyFmtErr)?;
-    let mut server = local::LocalServer::new(local_addr, local_port, server, server_port, time_out)?;
-    let _ = server.start();
+    let balancer = local::Balancer::new(&cfg.servers, cfg.strategy);
+    let checker = local::HealthChecker::new(balancer.clone());
+    let mut server = local::LocalServer::new(&cfg.local_address, cfg.local_port, balancer, cfg.timeout)?;
+    let pac = local::Pac::new();
+    let watcher = local::Watcher::new();
+    let _ = apply(&mut server, &checker, &pac, &watcher, &cfg)?;
+    checker.start();
+    watcher.start(Duration::from_secs(2));
+    signal::init();
+    let _ = start_admin(&cfg, &checker)?;
+    if let Some(metrics_port) = cfg.metrics_port {
+        let metrics = server.metrics().clone();
+        let checker = checker.clone();
+        metrics.register(move |out| checker.collect(out));
+        let _ = metrics.serve(&cfg.metrics_address, metrics_port)?;
+    }
+    while server.start() == Event::Reload {
+        info!("reload the config {:?}", options.config);
+        //keep the old config running if the new one is invalid
+        let rst = load(&options).and_then(|cfg| apply(&mut server, &checker, &pac, &watcher, &cfg));
+        if let Err(e) = rst {
+            error!("reload the config failed, {}", e.description());
+        }
+    }
     Ok(())
 }
 
 fn main() {
-    let _ = init_base_log();
-    let _ = try_main();
+    let options = match cli::parse(env::args().skip(1)) {
+        Ok(Action::Run(options)) => options,
+        Ok(Action::Help) => {
+            print!("{}", cli::usage("sslocal"));
+            return;
+        },
+        Ok(Action::Version) => {
+            println!("sslocal {}", cli::VERSION);
+            return;
+        },
+        Err(msg) => {
+            eprintln!("{}", msg);
+            eprint!("{}", cli::usage("sslocal"));
+            process::exit(1);
+        },
+    };
+    if options.verbose {
+        cli::init_verbose_log();
+    } else {
+        let _ = init_base_log();
+    }
+    if let Err(e) = try_main(options) {
+        error!("sslocal exit, {}", e.description());
+        eprintln!("sslocal exit, {}", e.description());
+        process::exit(1);
+    }
 }
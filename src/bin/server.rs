@@ -2,27 +2,152 @@ extern crate ss_rust;
 #[macro_use]
 extern crate log;
 
-extern crate base_config;
-use base_config::CFG;
-
 extern crate base_log;
 use base_log::init_base_log;
 
-use ss_rust::{ErrCode, server};
+use ss_rust::{ErrCode, server, signal, sip002};
+use ss_rust::admin::Admin;
+use ss_rust::config::{Config, Role, Mode};
+use ss_rust::cli;
+use ss_rust::cli::{Action, Options};
+use ss_rust::signal::Event;
 use ErrCode::*;
 
-fn try_main() -> Result<(), ErrCode> {
-    info!("{}", *CFG);
-    let local_addr = CFG["server"].as_str().ok_or(KeyFmtErr)?;
-    let local_port = CFG["server_port"].as_u64().ok_or(KeyFmtErr)? as u32;
-    let time_out = CFG["timeout"].as_u64().ok_or(KeyFmtErr)?;
+use std::env;
+use std::process;
+
+///read the config, every validation error is reported
+fn load(options:&Options) -> Result<Config, ErrCode> {
+    options.load(Role::Server).or_else(|errors| {
+        for e in errors {
+            error!("config error, {}", e);
+            eprintln!("config error, {}", e);
+        }
+        Err(ConfigErr)
+    })
+}
+
+///the values which don't need a rebind apply to the new connections
+fn apply(server:&mut server::Server, cfg:&Config) -> Result<(), ErrCode> {
+    let _ = server.set_listeners(&cfg.servers)?;
+    server.set_acl(server::Acl::new("acl", cfg.acl.clone()));
+    let _ = server.cache().set_config(cfg.dns.clone())?;
+    server.set_time_out(cfg.timeout);
+    server.set_connect_status(cfg.connect_status);
+    server.set_drain_time_out(cfg.drain_timeout);
+    let _ = server.set_access_log(cfg.access_log.as_ref().map(|path| path.as_str()))?;
+    server.set_limits(cfg.limits);
+    server.set_guard(cfg.guard);
+    Ok(())
+}
+
+fn start_admin(cfg:&Config, server:&server::Server) -> Result<(), ErrCode> {
+    if let Some(admin_port) = cfg.admin_port {
+        let mut admin = Admin::new(&cfg.admin_address, admin_port)?;
+        let cache = server.cache();
+        admin.register("dns", move |_| {
+            let stats = cache.stats();
+            format!("size {}\nhits {}\nmisses {}\nexpired {}\nevictions {}\nnegative_hits {}\ncoalesced {}\nrefreshes {}",
+                stats.size, stats.hits, stats.misses, stats.expired, stats.evictions,
+                stats.negative_hits, stats.coalesced, stats.refreshes)
+        });
+        let guard = server.guard();
+        admin.register("bans", move |_| {
+            let bans = guard.bans();
+            if bans.is_empty() {
+                return "no banned ip".to_string();
+            }
+            let lines:Vec<String> = bans.iter().map(|&(ip, left)| format!("{} {}s", ip, left)).collect();
+            lines.join("\n")
+        });
+        let guard = server.guard();
+        admin.register("unban", move |args| {
+            match args.parse() {
+                Ok(ip) if guard.unban(ip) => "ok".to_string(),
+                Ok(ip) => format!("{} is not banned", ip),
+                Err(_) => "usage: unban <ip>".to_string(),
+            }
+        });
+        admin.start();
+    }
+    Ok(())
+}
+
+fn try_main(options:Options) -> Result<(), ErrCode> {
+    let cfg = load(&options)?;
+    info!("{:?}", cfg);
+    if options.print_url {
+        for server in cfg.servers.iter() {
+            println!("{}", sip002::generate(server));
+        }
+        return Ok(());
+    }
+    if cfg.mode != Mode::TcpOnly {
+        warn!("udp relay is not supported, mode {} works as tcp_only", cfg.mode.as_str());
+    }
+    for listen in cfg.servers.iter() {
+        info!("{}:{} {}", listen.server, listen.server_port, listen.method);
+    }
 
-    let mut server = server::Server::new(local_addr, local_port, time_out)?;
-    let _ = server.start();
+    let mut server = server::Server::new(&cfg.servers, cfg.timeout)?;
+    let _ = apply(&mut server, &cfg)?;
+    match server.cache().load() {
+        Ok(count) => info!("{} dns entries are loaded", count),
+        Err(e) => warn!("load the dns cache failed, {}", e.description()),
+    }
+    signal::init();
+    let _ = start_admin(&cfg, &server)?;
+    if let Some(metrics_port) = cfg.metrics_port {
+        let metrics = server.metrics();
+        let cache = server.cache();
+        metrics.register(move |out| cache.collect(out));
+        let guard = server.guard();
+        metrics.register(move |out| guard.collect(out));
+        let _ = metrics.serve(&cfg.metrics_address, metrics_port)?;
+    }
+    if let Some(ref manager_address) = cfg.manager_address {
+        let _ = server.manager().serve(manager_address)?;
+    }
+    while server.start() == Event::Reload {
+        info!("reload the config {:?}", options.config);
+        //keep the old config running if the new one is invalid
+        let rst = load(&options).and_then(|cfg| apply(&mut server, &cfg));
+        if let Err(e) = rst {
+            error!("reload the config failed, {}", e.description());
+        }
+    }
+    match server.cache().save() {
+        Ok(count) => info!("{} dns entries are saved", count),
+        Err(e) => warn!("save the dns cache failed, {}", e.description()),
+    }
     Ok(())
 }
 
 fn main() {
-    let _ = init_base_log();
-    let _ = try_main();
+    let options = match cli::parse(env::args().skip(1)) {
+        Ok(Action::Run(options)) => options,
+        Ok(Action::Help) => {
+            print!("{}", cli::usage("ssserver"));
+            return;
+        },
+        Ok(Action::Version) => {
+            println!("ssserver {}", cli::VERSION);
+            return;
+        },
+        Err(msg) => {
+            eprintln!("{}", msg);
+            eprint!("{}", cli::usage("ssserver"));
+            process::exit(1);
+        },
+    };
+    if options.verbose {
+        cli::init_verbose_log();
+    } else {
+        let _ = init_base_log();
+    }
+    if let Err(e) = try_main(options) {
+        error!("ssserver exit, {}", e.description());
+        eprintln!("ssserver exit, {}", e.description());
+        process::exit(1);
+    }
 }
@@ -0,0 +1,204 @@
+//! command line of sslocal and ssserver, the flags of shadowsocks-libev.
+//! the flags override the values of the config file, the flags of a server
+//! are rejected when the config has a servers list.
+
+use serde_json::{Map, Value};
+
+use std::time::SystemTime;
+
+use log;
+use log::{LogLevelFilter, LogMetadata, LogRecord};
+
+use config;
+use helper;
+use config::{Config, ConfigError, Role};
+use sip002;
+
+pub const VERSION:&str = env!("CARGO_PKG_VERSION");
+
+///the flags of one server, -s -p -k -m and --server-url
+const SERVER_KEYS:&[&str] = &["server", "server_port", "password", "method", "plugin", "plugin_opts", "remarks"];
+
+pub enum Action {
+    Run(Options),
+    Help,
+    Version,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    pub config: Option<String>,
+    pub verbose: bool,
+    pub print_url: bool,
+    overrides: Vec<(&'static str, Value)>,
+}
+
+impl Options {
+
+    ///read the config file if given, apply the flags, then validate
+    pub fn load(&self, role:Role) -> Result<Config, Vec<ConfigError>> {
+        let value = match self.config {
+            Some(ref path) => config::read_json(path)?,
+            None => Value::Object(Map::new()),
+        };
+        self.apply(value, role)
+    }
+
+    fn apply(&self, mut value:Value, role:Role) -> Result<Config, Vec<ConfigError>> {
+        //the server flags are top-level keys, they would be merged with the list into an incomplete server
+        if !value["servers"].is_null() {
+            let flags:Vec<&str> = self.overrides.iter().map(|&(key, _)| key).filter(|key| SERVER_KEYS.contains(key)).collect();
+            if !flags.is_empty() {
+                let msg = format!("can not be combined with the flags of {}, set them in the entries of the list", flags.join(", "));
+                return Err(vec![ConfigError {key: "servers".to_string(), msg: msg}]);
+            }
+        }
+        if let Value::Object(ref mut map) = value {
+            for &(key, ref v) in self.overrides.iter() {
+                map.insert(key.to_string(), v.clone());
+            }
+        }
+        Config::from_value(&value, role)
+    }
+}
+
+pub fn usage(name:&str) -> String {
+    format!("usage: {} [options]
+
+  -c <config>          path to the config file
+  -s <server>          server address
+  -p <server_port>     server port
+  -b <local_address>   local address to bind
+  -l <local_port>      local port
+  -k <password>        password
+  -m <method>          cipher method, none or plain
+  -t <timeout>         timeout in seconds
+  -u                   enable udp relay, mode tcp_and_udp
+  -U                   only udp relay, mode udp_only
+  -v                   verbose mode, every log line on stderr
+  --server-url <url>   the server as a SIP002 ss:// url
+  --print-url          print the ss:// url of every server and exit
+  --help               print this help
+  --version            print the version
+", name)
+}
+
+///parse the arguments, without the program name
+pub fn parse<I>(args:I) -> Result<Action, String> where I: Iterator<Item=String> {
+    let mut options:Options = Default::default();
+    let mut args = args;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--help" | "-h" => return Ok(Action::Help),
+            "--version" => return Ok(Action::Version),
+            "-v" => options.verbose = true,
+            "--print-url" => options.print_url = true,
+            "--server-url" => {
+                let url = value_of(&arg, args.next())?;
+                let server = sip002::parse(&url).or(Err(format!("invalid server url {}", url)))?;
+                options.overrides.push(("server", Value::from(server.server)));
+                options.overrides.push(("server_port", Value::from(server.server_port)));
+                options.overrides.push(("password", Value::from(server.password)));
+                options.overrides.push(("method", Value::from(server.method)));
+                if let Some(plugin) = server.plugin {
+                    options.overrides.push(("plugin", Value::from(plugin)));
+                }
+                if let Some(plugin_opts) = server.plugin_opts {
+                    options.overrides.push(("plugin_opts", Value::from(plugin_opts)));
+                }
+                if let Some(remarks) = server.remarks {
+                    options.overrides.push(("remarks", Value::from(remarks)));
+                }
+            },
+            "-u" => options.overrides.push(("mode", Value::from("tcp_and_udp"))),
+            "-U" => options.overrides.push(("mode", Value::from("udp_only"))),
+            "-c" => options.config = Some(value_of(&arg, args.next())?),
+            "-s" => options.overrides.push(("server", Value::from(value_of(&arg, args.next())?))),
+            "-b" => options.overrides.push(("local_address", Value::from(value_of(&arg, args.next())?))),
+            "-k" => options.overrides.push(("password", Value::from(value_of(&arg, args.next())?))),
+            "-m" => options.overrides.push(("method", Value::from(value_of(&arg, args.next())?))),
+            "-p" => options.overrides.push(("server_port", Value::from(number_of(&arg, args.next())?))),
+            "-l" => options.overrides.push(("local_port", Value::from(number_of(&arg, args.next())?))),
+            "-t" => options.overrides.push(("timeout", Value::from(number_of(&arg, args.next())?))),
+            _ => return Err(format!("unknown option {}", arg)),
+        }
+    }
+    Ok(Action::Run(options))
+}
+
+///the logger of -v, the debug lines too
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+
+    fn enabled(&self, _:&LogMetadata) -> bool {
+        true
+    }
+
+    fn log(&self, record:&LogRecord) {
+        eprintln!("{} {:<5} {}: {}", helper::rfc3339(SystemTime::now()), record.level(), record.target(), record.args());
+    }
+}
+
+///log to stderr at the debug level, instead of the default logger
+pub fn init_verbose_log() {
+    let _ = log::set_logger(|max_level| {
+        max_level.set(LogLevelFilter::Debug);
+        Box::new(StderrLogger)
+    });
+}
+
+fn value_of(flag:&str, value:Option<String>) -> Result<String, String> {
+    value.ok_or(format!("{} requires a value", flag))
+}
+
+fn number_of(flag:&str, value:Option<String>) -> Result<u64, String> {
+    let value = value_of(flag, value)?;
+    value.parse().or(Err(format!("{} requires a number, got {}", flag, value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    fn options(args:&[&str]) -> Options {
+        match parse(args.iter().map(|arg| arg.to_string())) {
+            Ok(Action::Run(options)) => options,
+            _ => panic!("not a run"),
+        }
+    }
+
+    fn errors(args:&[&str], json:&str) -> Vec<String> {
+        match options(args).apply(serde_json::from_str(json).unwrap(), Role::Local) {
+            Ok(_) => Vec::new(),
+            Err(errors) => errors.iter().map(|e| e.to_string()).collect(),
+        }
+    }
+
+    const LIST:&str = r#"{"local_port": 1080, "servers": [
+        {"server": "a.test", "server_port": 8388, "password": "x"},
+        {"server": "b.test", "server_port": 8389, "password": "y"}
+    ]}"#;
+
+    #[test]
+    fn flags_override_the_file() {
+        let json = r#"{"server": "a.test", "server_port": 8388, "password": "x", "local_port": 1080}"#;
+        let cfg = options(&["-p", "9000", "-k", "z", "-t", "30"]).apply(serde_json::from_str(json).unwrap(), Role::Local).ok().unwrap();
+        assert_eq!(cfg.servers.len(), 1);
+        assert_eq!(cfg.servers[0].server_port, 9000);
+        assert_eq!(cfg.servers[0].password, "z");
+        assert_eq!(cfg.timeout, 30);
+    }
+
+    #[test]
+    fn server_flags_with_a_servers_list() {
+        let msg = "servers: can not be combined with the flags of server_port, set them in the entries of the list";
+        assert_eq!(errors(&["-p", "9000"], LIST), vec![msg]);
+        let msg = "servers: can not be combined with the flags of server, password, method, set them in the entries of the list";
+        assert_eq!(errors(&["-s", "c.test", "-k", "z", "-m", "plain"], LIST), vec![msg]);
+        assert_eq!(errors(&["--server-url", "ss://cGxhaW46eg@c.test:8390"], LIST).len(), 1);
+        //the other flags still apply
+        assert_eq!(errors(&["-l", "1081", "-t", "30"], LIST), Vec::<String>::new());
+    }
+}
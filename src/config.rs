@@ -0,0 +1,926 @@
+//! typed config, in the standard shadowsocks json format.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::{IpAddr, SocketAddr};
+
+use serde_json;
+use serde_json::Value;
+
+use helper::{parse_cidr, parse_port_range};
+
+///the methods the tunnel implements, both are the builtin encoding and ignore the password.
+///a real cipher, or table which is keyed by the password, is rejected rather than
+///silently tunneled without it
+pub const METHODS:&[&str] = &["none", "plain"];
+
+///which binary the config is for, the required keys differ
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum Role {
+    Local,
+    Server,
+}
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum Mode {
+    TcpOnly,
+    TcpAndUdp,
+    UdpOnly,
+}
+
+impl Mode {
+
+    pub fn from_name(mode:&str) -> Option<Mode> {
+        match mode {
+            "tcp_only" => Some(Mode::TcpOnly),
+            "tcp_and_udp" => Some(Mode::TcpAndUdp),
+            "udp_only" => Some(Mode::UdpOnly),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Mode::TcpOnly => "tcp_only",
+            Mode::TcpAndUdp => "tcp_and_udp",
+            Mode::UdpOnly => "udp_only",
+        }
+    }
+}
+
+///how sslocal picks the server of a new connection
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum Strategy {
+    RoundRobin,
+    Random,
+    LeastConnections,
+    LowestLatency,
+}
+
+impl Strategy {
+
+    pub fn from_name(strategy:&str) -> Option<Strategy> {
+        match strategy {
+            "round_robin" => Some(Strategy::RoundRobin),
+            "random" => Some(Strategy::Random),
+            "least_connections" => Some(Strategy::LeastConnections),
+            "lowest_latency" => Some(Strategy::LowestLatency),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Strategy::RoundRobin => "round_robin",
+            Strategy::Random => "random",
+            Strategy::LeastConnections => "least_connections",
+            Strategy::LowestLatency => "lowest_latency",
+        }
+    }
+}
+
+///a validation error, key is the path like servers[1].server_port
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct ConfigError {
+    pub key: String,
+    pub msg: String,
+}
+
+impl fmt::Display for ConfigError {
+
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.key, self.msg)
+    }
+}
+
+///the remote server for sslocal, the listener for ssserver
+#[derive(Clone)]
+pub struct ServerConfig {
+    pub server: String,
+    pub server_port: u32,
+    pub password: String,
+    pub method: String,
+    pub plugin: Option<String>,
+    pub plugin_opts: Option<String>,
+    pub remarks: Option<String>,
+    pub acl: Option<AclConfig>, //ssserver only, further restricts this listener
+}
+
+///the password is not logged
+impl fmt::Debug for ServerConfig {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ServerConfig")
+            .field("server", &self.server)
+            .field("server_port", &self.server_port)
+            .field("password", &"***")
+            .field("method", &self.method)
+            .field("plugin", &self.plugin)
+            .field("plugin_opts", &self.plugin_opts)
+            .field("remarks", &self.remarks)
+            .field("acl", &self.acl)
+            .finish()
+    }
+}
+
+///the probes of the servers of sslocal
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct HealthCheck {
+    pub interval: u64, //seconds between the rounds
+    pub timeout: u64, //seconds of one probe
+    pub fall: u64, //consecutive failures to mark a server down
+    pub rise: u64, //consecutive successes to bring it back
+    pub target: Option<String>, //http url requested through the tunnel
+}
+
+///destinations of ssserver, a domain matches its subdomains too
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct AclList {
+    pub cidrs: Vec<(IpAddr, u8)>,
+    pub domains: Vec<String>,
+    pub ports: Vec<(u16, u16)>,
+}
+
+///which targets ssserver may connect, see server::acl
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct AclConfig {
+    pub block_private: bool, //loopback, link-local, private and multicast networks
+    pub allow: AclList,
+    pub deny: AclList,
+}
+
+impl Default for AclConfig {
+    fn default() -> Self {
+        AclConfig {
+            block_private: true,
+            allow: Default::default(),
+            deny: Default::default(),
+        }
+    }
+}
+
+///the dns cache of ssserver
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct DnsConfig {
+    pub min_ttl: u64, //seconds, also the ttl of the system resolver
+    pub max_ttl: u64,
+    pub cache_size: usize, //entries, the least recently used is evicted
+    pub nameservers: Vec<SocketAddr>, //empty for the system resolver
+    pub timeout: u64, //seconds of one query
+    pub retries: u64, //rounds over the nameservers after the first
+    pub negative_ttl: u64, //seconds a failed lookup is cached, 0 disables
+    pub refresh_before: u64, //a hit this many seconds before the expiry refreshes, 0 disables
+    pub hosts: HashMap<String, Vec<IpAddr>>, //pinned names, not resolved nor cached
+    pub cache_file: Option<String>, //the cache is saved on exit and loaded at startup
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        DnsConfig {
+            min_ttl: 60,
+            max_ttl: 3600,
+            cache_size: 10000,
+            nameservers: Vec::new(),
+            timeout: 2,
+            retries: 2,
+            negative_ttl: 10,
+            refresh_before: 5,
+            hosts: HashMap::new(),
+            cache_file: None,
+        }
+    }
+}
+
+///bytes per second of one direction, 0 is unlimited
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct RateLimit {
+    pub up: u64, //from the client to the target
+    pub down: u64,
+    pub burst: u64, //bytes which may pass at once, 0 is one second of the rate
+}
+
+///token buckets of the tunnels, the user ones are the listeners of ssserver
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct LimitConfig {
+    pub connection: RateLimit,
+    pub user: RateLimit,
+    pub global: RateLimit,
+}
+
+///the clients of ssserver, by the source ip
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct GuardConfig {
+    pub max_connections_per_ip: u64, //concurrent, 0 is unlimited
+    pub max_failures: u64, //failed handshakes within find_time which ban the ip, 0 disables
+    pub find_time: u64, //seconds
+    pub ban_time: u64, //seconds
+}
+
+impl Default for GuardConfig {
+    fn default() -> Self {
+        GuardConfig {
+            max_connections_per_ip: 0,
+            max_failures: 0,
+            find_time: 60,
+            ban_time: 600,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub servers: Vec<ServerConfig>,
+    pub local_address: String,
+    pub local_port: u32,
+    pub timeout: u64, //seconds to dial the target, ssserver waits a random part of it for the head, at most a minute
+    pub mode: Mode,
+    pub strategy: Strategy,
+    pub health_check: Option<HealthCheck>,
+    pub rules: Option<String>, //path of the routing rules of sslocal
+    pub gfwlist: Option<String>, //path of the gfwlist of sslocal
+    pub pac_address: String,
+    pub pac_port: Option<u32>, //serve proxy.pac from sslocal
+    pub acl: AclConfig, //destinations of ssserver
+    pub dns: DnsConfig,
+    pub connect_status: bool,
+    pub drain_timeout: u64,
+    pub admin_address: String,
+    pub admin_port: Option<u32>,
+    pub metrics_address: String,
+    pub metrics_port: Option<u32>, //serve the prometheus metrics
+    pub manager_address: Option<String>, //ip:port of udp or the path of a unix socket
+    pub access_log: Option<String>, //path of the json lines of the sessions
+    pub limits: LimitConfig,
+    pub guard: GuardConfig,
+}
+
+impl Config {
+
+    ///read and validate the config file, every invalid key is reported
+    pub fn from_file(path:&str, role:Role) -> Result<Config, Vec<ConfigError>> {
+        let value = read_json(path)?;
+        Self::from_value(&value, role)
+    }
+
+    pub fn from_value(value:&Value, role:Role) -> Result<Config, Vec<ConfigError>> {
+        let mut parser = Parser {errors: Vec::new()};
+        if !value.is_object() {
+            parser.error("", "must be an object");
+            return Err(parser.errors);
+        }
+
+        let mut servers = Vec::new();
+        if !value["server"].is_null() || !value["server_port"].is_null() {
+            if let Some(server) = parser.server(value, "") {
+                servers.push(server);
+            }
+        }
+        match value["servers"] {
+            Value::Null => {},
+            Value::Array(ref list) => {
+                for (i, item) in list.iter().enumerate() {
+                    let prefix = format!("servers[{}].", i);
+                    if !item.is_object() {
+                        parser.error(&format!("servers[{}]", i), "must be an object");
+                    } else if let Some(server) = parser.server(item, &prefix) {
+                        servers.push(server);
+                    }
+                }
+            },
+            _ => parser.error("servers", "must be an array"),
+        }
+        if servers.is_empty() && parser.errors.is_empty() {
+            parser.error("server", "is required, or a non-empty servers list");
+        }
+
+        let local_address = parser.string(value, "local_address").unwrap_or("127.0.0.1".to_string());
+        let local_port = match role {
+            Role::Local => parser.required_port(value, "local_port"),
+            Role::Server => parser.port(value, "local_port"),
+        };
+        let timeout = parser.u64(value, "timeout").unwrap_or(300);
+        let mode = match parser.string(value, "mode") {
+            Some(mode) => Mode::from_name(&mode).unwrap_or_else(|| {
+                parser.error("mode", "must be tcp_only, tcp_and_udp or udp_only");
+                Mode::TcpOnly
+            }),
+            None => Mode::TcpOnly,
+        };
+        let strategy = match parser.string(value, "strategy") {
+            Some(strategy) => Strategy::from_name(&strategy).unwrap_or_else(|| {
+                parser.error("strategy", "must be round_robin, random, least_connections or lowest_latency");
+                Strategy::RoundRobin
+            }),
+            None => Strategy::RoundRobin,
+        };
+        let health_check = match value["health_check"] {
+            Value::Null => None,
+            Value::Object(_) => parser.health_check(&value["health_check"]),
+            _ => {
+                parser.error("health_check", "must be an object");
+                None
+            },
+        };
+        let rules = parser.string(value, "rules");
+        let gfwlist = parser.string(value, "gfwlist");
+        let pac_address = parser.string(value, "pac_address").unwrap_or("127.0.0.1".to_string());
+        let pac_port = parser.port(value, "pac_port");
+        let acl = match value["acl"] {
+            Value::Null => Default::default(),
+            Value::Object(_) => parser.acl(&value["acl"], "acl", true).unwrap_or_default(),
+            _ => {
+                parser.error("acl", "must be an object");
+                Default::default()
+            },
+        };
+        let dns = match value["dns"] {
+            Value::Null => Default::default(),
+            Value::Object(_) => parser.dns(&value["dns"]).unwrap_or_default(),
+            _ => {
+                parser.error("dns", "must be an object");
+                Default::default()
+            },
+        };
+        let guard = match value["guard"] {
+            Value::Null => Default::default(),
+            Value::Object(_) => parser.guard(&value["guard"]).unwrap_or_default(),
+            _ => {
+                parser.error("guard", "must be an object");
+                Default::default()
+            },
+        };
+        let connect_status = parser.bool(value, "connect_status").unwrap_or(false);
+        let drain_timeout = parser.u64(value, "drain_timeout").unwrap_or(30);
+        let admin_address = parser.string(value, "admin_address").unwrap_or("127.0.0.1".to_string());
+        let admin_port = parser.port(value, "admin_port");
+        let metrics_address = parser.string(value, "metrics_address").unwrap_or("127.0.0.1".to_string());
+        let metrics_port = parser.port(value, "metrics_port");
+        let manager_address = parser.string(value, "manager_address");
+        let access_log = parser.string(value, "access_log");
+        let limits = match value["limits"] {
+            Value::Null => Default::default(),
+            Value::Object(_) => LimitConfig {
+                connection: parser.rate_limit(&value["limits"], "connection"),
+                user: parser.rate_limit(&value["limits"], "user"),
+                global: parser.rate_limit(&value["limits"], "global"),
+            },
+            _ => {
+                parser.error("limits", "must be an object");
+                Default::default()
+            },
+        };
+
+        if !parser.errors.is_empty() {
+            return Err(parser.errors);
+        }
+        Ok(Config {
+            servers: servers,
+            local_address: local_address,
+            local_port: local_port.unwrap_or(0),
+            timeout: timeout,
+            mode: mode,
+            strategy: strategy,
+            health_check: health_check,
+            rules: rules,
+            gfwlist: gfwlist,
+            pac_address: pac_address,
+            pac_port: pac_port,
+            acl: acl,
+            dns: dns,
+            connect_status: connect_status,
+            drain_timeout: drain_timeout,
+            admin_address: admin_address,
+            admin_port: admin_port,
+            metrics_address: metrics_address,
+            metrics_port: metrics_port,
+            manager_address: manager_address,
+            access_log: access_log,
+            limits: limits,
+            guard: guard,
+        })
+    }
+}
+
+///collect the errors instead of stopping at the first one
+struct Parser {
+    errors: Vec<ConfigError>,
+}
+
+impl Parser {
+
+    fn error(&mut self, key:&str, msg:&str) {
+        self.errors.push(ConfigError {key: key.to_string(), msg: msg.to_string()});
+    }
+
+    fn string(&mut self, value:&Value, key:&str) -> Option<String> {
+        self.prefixed_string(value, "", key)
+    }
+
+    fn prefixed_string(&mut self, value:&Value, prefix:&str, key:&str) -> Option<String> {
+        match value[key] {
+            Value::Null => None,
+            Value::String(ref s) => Some(s.clone()),
+            _ => {
+                self.error(&format!("{}{}", prefix, key), "must be a string");
+                None
+            },
+        }
+    }
+
+    fn u64(&mut self, value:&Value, key:&str) -> Option<u64> {
+        self.prefixed_u64(value, "", key)
+    }
+
+    fn prefixed_u64(&mut self, value:&Value, prefix:&str, key:&str) -> Option<u64> {
+        match value[key] {
+            Value::Null => None,
+            ref v => v.as_u64().or_else(|| {
+                self.error(&format!("{}{}", prefix, key), "must be a non-negative integer");
+                None
+            }),
+        }
+    }
+
+    fn bool(&mut self, value:&Value, key:&str) -> Option<bool> {
+        match value[key] {
+            Value::Null => None,
+            ref v => v.as_bool().or_else(|| {
+                self.error(key, "must be true or false");
+                None
+            }),
+        }
+    }
+
+    fn port(&mut self, value:&Value, key:&str) -> Option<u32> {
+        self.prefixed_port(value, "", key)
+    }
+
+    fn prefixed_port(&mut self, value:&Value, prefix:&str, key:&str) -> Option<u32> {
+        match value[key] {
+            Value::Null => None,
+            ref v => match v.as_u64() {
+                Some(port) if port > 0 && port <= 65535 => Some(port as u32),
+                _ => {
+                    self.error(&format!("{}{}", prefix, key), "must be a port between 1 and 65535");
+                    None
+                },
+            },
+        }
+    }
+
+    fn required_port(&mut self, value:&Value, key:&str) -> Option<u32> {
+        if value[key].is_null() {
+            self.error(key, "is required");
+            return None;
+        }
+        self.port(value, key)
+    }
+
+    fn health_check(&mut self, value:&Value) -> Option<HealthCheck> {
+        let prefix = "health_check.";
+        let count = self.errors.len();
+        let interval = self.prefixed_u64(value, prefix, "interval").unwrap_or(30);
+        let timeout = self.prefixed_u64(value, prefix, "timeout").unwrap_or(5);
+        let fall = self.prefixed_u64(value, prefix, "fall").unwrap_or(3);
+        let rise = self.prefixed_u64(value, prefix, "rise").unwrap_or(2);
+        let target = self.prefixed_string(value, prefix, "target");
+        if interval == 0 || timeout == 0 || fall == 0 || rise == 0 {
+            self.error("health_check", "interval, timeout, fall and rise must be positive");
+        }
+        if let Some(ref target) = target {
+            if parse_http_url(target).is_none() {
+                self.error("health_check.target", "must be an url like http://host:port/path");
+            }
+        }
+        if self.errors.len() > count {
+            return None;
+        }
+        Some(HealthCheck {
+            interval: interval,
+            timeout: timeout,
+            fall: fall,
+            rise: rise,
+            target: target,
+        })
+    }
+
+    fn dns(&mut self, value:&Value) -> Option<DnsConfig> {
+        let prefix = "dns.";
+        let count = self.errors.len();
+        let default:DnsConfig = Default::default();
+        let min_ttl = self.prefixed_u64(value, prefix, "min_ttl").unwrap_or(default.min_ttl);
+        let max_ttl = self.prefixed_u64(value, prefix, "max_ttl").unwrap_or(default.max_ttl);
+        let cache_size = self.prefixed_u64(value, prefix, "cache_size").map(|size| size as usize).unwrap_or(default.cache_size);
+        let timeout = self.prefixed_u64(value, prefix, "timeout").unwrap_or(default.timeout);
+        let retries = self.prefixed_u64(value, prefix, "retries").unwrap_or(default.retries);
+        let negative_ttl = self.prefixed_u64(value, prefix, "negative_ttl").unwrap_or(default.negative_ttl);
+        let refresh_before = self.prefixed_u64(value, prefix, "refresh_before").unwrap_or(default.refresh_before);
+        let cache_file = self.prefixed_string(value, prefix, "cache_file");
+        let hosts = self.hosts(&value["hosts"]);
+        let mut nameservers = Vec::new();
+        for (i, item) in self.array(value, "dns", "nameservers").iter().enumerate() {
+            //the port is 53 by default
+            let addr = item.as_str().and_then(|s| {
+                s.parse::<SocketAddr>().ok().or_else(|| s.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, 53)))
+            });
+            match addr {
+                Some(addr) => nameservers.push(addr),
+                None => self.error(&format!("dns.nameservers[{}]", i), "must be an ip or ip:port"),
+            }
+        }
+        if min_ttl > max_ttl {
+            self.error("dns.min_ttl", "must not be greater than max_ttl");
+        }
+        if timeout == 0 {
+            self.error("dns.timeout", "must be positive");
+        }
+        if self.errors.len() > count {
+            return None;
+        }
+        Some(DnsConfig {
+            min_ttl: min_ttl,
+            max_ttl: max_ttl,
+            cache_size: cache_size,
+            nameservers: nameservers,
+            timeout: timeout,
+            retries: retries,
+            negative_ttl: negative_ttl,
+            refresh_before: refresh_before,
+            hosts: hosts,
+            cache_file: cache_file,
+        })
+    }
+
+    fn guard(&mut self, value:&Value) -> Option<GuardConfig> {
+        let prefix = "guard.";
+        let count = self.errors.len();
+        let default:GuardConfig = Default::default();
+        let max_connections_per_ip = self.prefixed_u64(value, prefix, "max_connections_per_ip").unwrap_or(default.max_connections_per_ip);
+        let max_failures = self.prefixed_u64(value, prefix, "max_failures").unwrap_or(default.max_failures);
+        let find_time = self.prefixed_u64(value, prefix, "find_time").unwrap_or(default.find_time);
+        let ban_time = self.prefixed_u64(value, prefix, "ban_time").unwrap_or(default.ban_time);
+        if find_time == 0 {
+            self.error("guard.find_time", "must be positive");
+        }
+        if ban_time == 0 {
+            self.error("guard.ban_time", "must be positive");
+        }
+        if self.errors.len() > count {
+            return None;
+        }
+        Some(GuardConfig {
+            max_connections_per_ip: max_connections_per_ip,
+            max_failures: max_failures,
+            find_time: find_time,
+            ban_time: ban_time,
+        })
+    }
+
+    ///{"db.internal": "10.0.0.5", "dual.test": ["127.0.0.1", "::1"]}
+    fn hosts(&mut self, value:&Value) -> HashMap<String, Vec<IpAddr>> {
+        let mut hosts = HashMap::new();
+        let map = match *value {
+            Value::Null => return hosts,
+            Value::Object(ref map) => map,
+            _ => {
+                self.error("dns.hosts", "must be an object");
+                return hosts;
+            },
+        };
+        for (name, item) in map.iter() {
+            let key = format!("dns.hosts.{}", name);
+            let items = match *item {
+                Value::Array(ref items) => items.clone(),
+                ref v => vec![v.clone()],
+            };
+            let ips:Vec<IpAddr> = items.iter().filter_map(|ip| ip.as_str().and_then(|s| s.parse().ok())).collect();
+            if ips.is_empty() || ips.len() != items.len() {
+                self.error(&key, "must be an ip or a list of ips");
+                continue;
+            }
+            let _ = hosts.insert(name.trim_end_matches('.').to_lowercase(), ips);
+        }
+        hosts
+    }
+
+    ///{"up": 1048576, "down": 4194304, "burst": 262144}
+    fn rate_limit(&mut self, value:&Value, key:&str) -> RateLimit {
+        let prefix = format!("limits.{}.", key);
+        match value[key] {
+            Value::Null => Default::default(),
+            Value::Object(_) => RateLimit {
+                up: self.prefixed_u64(&value[key], &prefix, "up").unwrap_or(0),
+                down: self.prefixed_u64(&value[key], &prefix, "down").unwrap_or(0),
+                burst: self.prefixed_u64(&value[key], &prefix, "burst").unwrap_or(0),
+            },
+            _ => {
+                self.error(&format!("limits.{}", key), "must be an object");
+                Default::default()
+            },
+        }
+    }
+
+    ///the acl of a listener only adds restrictions, so its block_private is off by default
+    fn acl(&mut self, value:&Value, key:&str, block_private:bool) -> Option<AclConfig> {
+        let count = self.errors.len();
+        let block_private = match value["block_private"] {
+            Value::Null => block_private,
+            ref v => v.as_bool().unwrap_or_else(|| {
+                self.error(&format!("{}.block_private", key), "must be true or false");
+                block_private
+            }),
+        };
+        let allow = self.acl_list(&value["allow"], &format!("{}.allow", key));
+        let deny = self.acl_list(&value["deny"], &format!("{}.deny", key));
+        if self.errors.len() > count {
+            return None;
+        }
+        Some(AclConfig {
+            block_private: block_private,
+            allow: allow,
+            deny: deny,
+        })
+    }
+
+    ///{"cidrs": ["10.0.0.0/8"], "domains": ["example.com"], "ports": [25, "8000-9000"]}
+    fn acl_list(&mut self, value:&Value, key:&str) -> AclList {
+        let mut list:AclList = Default::default();
+        match *value {
+            Value::Null => return list,
+            Value::Object(_) => {},
+            _ => {
+                self.error(key, "must be an object");
+                return list;
+            },
+        }
+        for (i, item) in self.array(value, key, "cidrs").iter().enumerate() {
+            match item.as_str().and_then(parse_cidr) {
+                Some(cidr) => list.cidrs.push(cidr),
+                None => self.error(&format!("{}.cidrs[{}]", key, i), "must be a cidr like 10.0.0.0/8"),
+            }
+        }
+        for (i, item) in self.array(value, key, "domains").iter().enumerate() {
+            match item.as_str() {
+                Some(domain) if !domain.is_empty() => list.domains.push(domain.trim_start_matches('.').to_lowercase()),
+                _ => self.error(&format!("{}.domains[{}]", key, i), "must be a domain"),
+            }
+        }
+        for (i, item) in self.array(value, key, "ports").iter().enumerate() {
+            let range = match *item {
+                Value::String(ref s) => parse_port_range(s),
+                ref v => v.as_u64().and_then(|p| if p <= 65535 { Some((p as u16, p as u16)) } else { None }),
+            };
+            match range {
+                Some(range) => list.ports.push(range),
+                None => self.error(&format!("{}.ports[{}]", key, i), "must be a port or a range like 8000-9000"),
+            }
+        }
+        list
+    }
+
+    fn array(&mut self, value:&Value, prefix:&str, key:&str) -> Vec<Value> {
+        match value[key] {
+            Value::Null => Vec::new(),
+            Value::Array(ref items) => items.clone(),
+            _ => {
+                self.error(&format!("{}.{}", prefix, key), "must be an array");
+                Vec::new()
+            },
+        }
+    }
+
+    fn server(&mut self, value:&Value, prefix:&str) -> Option<ServerConfig> {
+        let count = self.errors.len();
+        let server = self.prefixed_string(value, prefix, "server");
+        if value["server"].is_null() {
+            self.error(&format!("{}server", prefix), "is required");
+        }
+        let server_port = self.prefixed_port(value, prefix, "server_port");
+        if value["server_port"].is_null() {
+            self.error(&format!("{}server_port", prefix), "is required");
+        }
+        let password = self.prefixed_string(value, prefix, "password").unwrap_or(String::new());
+        let method = self.prefixed_string(value, prefix, "method").unwrap_or("none".to_string());
+        let plugin = self.prefixed_string(value, prefix, "plugin");
+        let plugin_opts = self.prefixed_string(value, prefix, "plugin_opts");
+        let remarks = self.prefixed_string(value, prefix, "remarks");
+        //the top level acl is the global one
+        let acl = match value["acl"] {
+            _ if prefix.is_empty() => None,
+            Value::Null => None,
+            Value::Object(_) => self.acl(&value["acl"], &format!("{}acl", prefix), false),
+            _ => {
+                self.error(&format!("{}acl", prefix), "must be an object");
+                None
+            },
+        };
+        if !METHODS.contains(&method.as_str()) {
+            self.error(&format!("{}method", prefix), "is not supported, only none and plain");
+        }
+        if self.errors.len() > count {
+            return None;
+        }
+        Some(ServerConfig {
+            server: server.unwrap_or(String::new()),
+            server_port: server_port.unwrap_or(0),
+            password: password,
+            method: method,
+            plugin: plugin,
+            plugin_opts: plugin_opts,
+            remarks: remarks,
+            acl: acl,
+        })
+    }
+}
+
+///http://host[:port][/path] to (host, port, path)
+pub fn parse_http_url(url:&str) -> Option<(String, u16, String)> {
+    if !url.starts_with("http://") {
+        return None;
+    }
+    let rest = &url[7..];
+    let (host_port, path) = match rest.find('/') {
+        Some(pos) => (&rest[..pos], &rest[pos..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match host_port.rfind(':') {
+        Some(pos) if !host_port.ends_with(']') => (&host_port[..pos], host_port[pos + 1..].parse().ok()?),
+        _ => (host_port, 80),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() || port == 0 {
+        return None;
+    }
+    Some((host.to_string(), port, path.to_string()))
+}
+
+///read the json config file without validating it
+pub fn read_json(path:&str) -> Result<Value, Vec<ConfigError>> {
+    let file = File::open(path).map_err(|e| {
+        vec![ConfigError {key: path.to_string(), msg: format!("can not open, {}", e)}]
+    })?;
+    serde_json::from_reader(BufReader::new(file)).map_err(|e| {
+        vec![ConfigError {key: path.to_string(), msg: format!("invalid json, {}", e)}]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(json:&str, role:Role) -> Config {
+        Config::from_value(&serde_json::from_str(json).unwrap(), role).unwrap()
+    }
+
+    fn errors(json:&str, role:Role) -> Vec<String> {
+        let errors = Config::from_value(&serde_json::from_str(json).unwrap(), role).unwrap_err();
+        errors.iter().map(|e| e.to_string()).collect()
+    }
+
+    #[test]
+    fn defaults() {
+        let cfg = config(r#"{"server": "0.0.0.0", "server_port": 8388, "password": "secret"}"#, Role::Server);
+        assert_eq!(cfg.servers.len(), 1);
+        assert_eq!(cfg.servers[0].method, "none");
+        assert_eq!(cfg.servers[0].acl, None);
+        assert_eq!(cfg.timeout, 300);
+        assert_eq!(cfg.mode, Mode::TcpOnly);
+        assert_eq!(cfg.strategy, Strategy::RoundRobin);
+        assert_eq!(cfg.acl, Default::default());
+        assert!(cfg.acl.block_private);
+        assert_eq!(cfg.dns, Default::default());
+        assert_eq!(cfg.limits, Default::default());
+        assert_eq!(cfg.guard, Default::default());
+        assert_eq!(cfg.local_port, 0);
+        assert_eq!(cfg.pac_port, None);
+    }
+
+    #[test]
+    fn missing_keys() {
+        assert_eq!(errors(r#"{}"#, Role::Server), vec!["server: is required, or a non-empty servers list"]);
+        assert_eq!(errors(r#"{"server": "127.0.0.1", "server_port": 8388}"#, Role::Local), vec!["local_port: is required"]);
+        assert_eq!(errors(r#"{"server_port": 8388}"#, Role::Server), vec!["server: is required"]);
+        assert_eq!(errors(r#"[]"#, Role::Server), vec![": must be an object"]);
+    }
+
+    #[test]
+    fn wrong_types() {
+        let json = r#"{"server": 1, "server_port": "8388", "timeout": "300", "mode": "tcp", "strategy": "fastest",
+            "connect_status": 1, "rules": [], "acl": [], "dns": 1, "health_check": true, "limits": "1M", "guard": 0}"#;
+        assert_eq!(errors(json, Role::Server), vec![
+            "server: must be a string",
+            "server_port: must be a port between 1 and 65535",
+            "timeout: must be a non-negative integer",
+            "mode: must be tcp_only, tcp_and_udp or udp_only",
+            "strategy: must be round_robin, random, least_connections or lowest_latency",
+            "health_check: must be an object",
+            "rules: must be a string",
+            "acl: must be an object",
+            "dns: must be an object",
+            "guard: must be an object",
+            "connect_status: must be true or false",
+            "limits: must be an object",
+        ]);
+    }
+
+    #[test]
+    fn out_of_range() {
+        let json = r#"{"server": "::", "server_port": 70000, "local_port": 0, "timeout": -1, "method": "aes-256-gcm",
+            "health_check": {"interval": 0}, "dns": {"min_ttl": 10, "max_ttl": 5, "timeout": 0},
+            "guard": {"find_time": 0, "ban_time": 0}}"#;
+        assert_eq!(errors(json, Role::Server), vec![
+            "server_port: must be a port between 1 and 65535",
+            "method: is not supported, only none and plain",
+            "local_port: must be a port between 1 and 65535",
+            "timeout: must be a non-negative integer",
+            "health_check: interval, timeout, fall and rise must be positive",
+            "dns.min_ttl: must not be greater than max_ttl",
+            "dns.timeout: must be positive",
+            "guard.find_time: must be positive",
+            "guard.ban_time: must be positive",
+        ]);
+        assert_eq!(errors(r#"{"server": "::", "server_port": 1, "method": "table"}"#, Role::Server), vec!["method: is not supported, only none and plain"]);
+    }
+
+    #[test]
+    fn servers_list() {
+        let json = r#"{"servers": [
+            {"server": "127.0.0.1", "server_port": 8388, "method": "plain", "remarks": "alice",
+             "acl": {"deny": {"ports": [25]}}},
+            {"server": "::1", "server_port": 8389}
+        ]}"#;
+        let cfg = config(json, Role::Server);
+        assert_eq!(cfg.servers.len(), 2);
+        assert_eq!(cfg.servers[0].remarks, Some("alice".to_string()));
+        assert_eq!(cfg.servers[0].method, "plain");
+        //the acl of a listener only restricts
+        let acl = cfg.servers[0].acl.clone().unwrap();
+        assert!(!acl.block_private);
+        assert_eq!(acl.deny.ports, vec![(25, 25)]);
+        assert_eq!(cfg.servers[1].server_port, 8389);
+
+        let json = r#"{"servers": [
+            {"server": "127.0.0.1", "server_port": 8388},
+            {"server": "127.0.0.1"},
+            "127.0.0.1:8390",
+            {"server": "127.0.0.1", "server_port": 8391, "acl": {"block_private": "no", "allow": {"cidrs": ["10.0.0.0/33"]}}}
+        ]}"#;
+        assert_eq!(errors(json, Role::Server), vec![
+            "servers[1].server_port: is required",
+            "servers[2]: must be an object",
+            "servers[3].acl.block_private: must be true or false",
+            "servers[3].acl.allow.cidrs[0]: must be a cidr like 10.0.0.0/8",
+        ]);
+        assert_eq!(errors(r#"{"servers": {}}"#, Role::Server), vec!["servers: must be an array"]);
+        assert_eq!(errors(r#"{"servers": []}"#, Role::Server), vec!["server: is required, or a non-empty servers list"]);
+    }
+
+    #[test]
+    fn acl_section() {
+        let json = r#"{"server": "::", "server_port": 8388, "acl": {"block_private": false,
+            "allow": {"domains": ["Intranet.Example.com", ".corp.test"], "ports": [80, "443", "8000-9000"]},
+            "deny": {"cidrs": ["10.0.0.0/8", "fd00::/8", "1.2.3.4"]}}}"#;
+        let acl = config(json, Role::Server).acl;
+        assert!(!acl.block_private);
+        assert_eq!(acl.allow.domains, vec!["intranet.example.com", "corp.test"]);
+        assert_eq!(acl.allow.ports, vec![(80, 80), (443, 443), (8000, 9000)]);
+        assert_eq!(acl.deny.cidrs, vec![
+            ("10.0.0.0".parse().unwrap(), 8),
+            ("fd00::".parse().unwrap(), 8),
+            ("1.2.3.4".parse().unwrap(), 32),
+        ]);
+
+        let json = r#"{"server": "::", "server_port": 8388, "acl": {"allow": [],
+            "deny": {"cidrs": "10.0.0.0/8", "domains": [""], "ports": [65536, "9000-8000"]}}}"#;
+        assert_eq!(errors(json, Role::Server), vec![
+            "acl.allow: must be an object",
+            "acl.deny.cidrs: must be an array",
+            "acl.deny.domains[0]: must be a domain",
+            "acl.deny.ports[0]: must be a port or a range like 8000-9000",
+            "acl.deny.ports[1]: must be a port or a range like 8000-9000",
+        ]);
+    }
+
+    #[test]
+    fn limits_and_guard() {
+        let json = r#"{"server": "::", "server_port": 8388,
+            "limits": {"connection": {"up": 1000, "down": 2000}, "user": {"down": 3000, "burst": 500}},
+            "guard": {"max_connections_per_ip": 8, "max_failures": 5, "ban_time": 3600}}"#;
+        let cfg = config(json, Role::Server);
+        assert_eq!(cfg.limits.connection, RateLimit {up: 1000, down: 2000, burst: 0});
+        assert_eq!(cfg.limits.user, RateLimit {up: 0, down: 3000, burst: 500});
+        assert_eq!(cfg.limits.global, Default::default());
+        assert_eq!(cfg.guard, GuardConfig {max_connections_per_ip: 8, max_failures: 5, find_time: 60, ban_time: 3600});
+
+        let json = r#"{"server": "::", "server_port": 8388,
+            "limits": {"connection": 1000, "global": {"up": -1, "burst": "1M"}},
+            "guard": {"max_failures": 1.5}}"#;
+        assert_eq!(errors(json, Role::Server), vec![
+            "guard.max_failures: must be a non-negative integer",
+            "limits.connection: must be an object",
+            "limits.global.up: must be a non-negative integer",
+            "limits.global.burst: must be a non-negative integer",
+        ]);
+    }
+}
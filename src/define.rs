@@ -1,4 +1,5 @@
 use std::net::{Shutdown, TcpStream, Ipv4Addr};
+use std::io;
 
 #[derive(PartialEq, Eq, Debug, Copy, Clone)]
 pub enum ErrCode {
@@ -87,3 +88,72 @@ impl Ip {
     }
 }
 
+
+///socks5 reply field (REP), rfc1928 section 6
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum Reply {
+    Succeeded = 0,
+    GeneralFailure = 1,
+    NotAllowed = 2,
+    NetworkUnreachable = 3,
+    HostUnreachable = 4,
+    ConnectionRefused = 5,
+    TtlExpired = 6,
+    CommandNotSupported = 7,
+    AddrTypeNotSupported = 8,
+}
+
+impl Reply {
+
+    pub fn description(&self) -> &'static str {
+        match *self {
+            Reply::Succeeded => "succeeded",
+            Reply::GeneralFailure => "general SOCKS server failure",
+            Reply::NotAllowed => "connection not allowed by ruleset",
+            Reply::NetworkUnreachable => "network unreachable",
+            Reply::HostUnreachable => "host unreachable",
+            Reply::ConnectionRefused => "connection refused",
+            Reply::TtlExpired => "TTL expired",
+            Reply::CommandNotSupported => "command not supported",
+            Reply::AddrTypeNotSupported => "address type not supported",
+        }
+    }
+
+    pub fn from_u8(code:u8) -> Reply {
+        match code {
+            0 => Reply::Succeeded,
+            2 => Reply::NotAllowed,
+            3 => Reply::NetworkUnreachable,
+            4 => Reply::HostUnreachable,
+            5 => Reply::ConnectionRefused,
+            6 => Reply::TtlExpired,
+            7 => Reply::CommandNotSupported,
+            8 => Reply::AddrTypeNotSupported,
+            _ => Reply::GeneralFailure,
+        }
+    }
+}
+
+impl From<Reply> for u8 {
+
+    fn from(rep:Reply) -> Self {
+        rep as u8
+    }
+}
+
+impl<'a> From<&'a io::Error> for Reply {
+
+    ///map a failed connect to the closest reply code
+    fn from(e:&'a io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::ConnectionRefused => Reply::ConnectionRefused,
+            io::ErrorKind::NetworkUnreachable => Reply::NetworkUnreachable,
+            io::ErrorKind::HostUnreachable => Reply::HostUnreachable,
+            io::ErrorKind::AddrNotAvailable => Reply::HostUnreachable,
+            io::ErrorKind::TimedOut => Reply::TtlExpired,
+            io::ErrorKind::PermissionDenied => Reply::NotAllowed,
+            io::ErrorKind::InvalidInput => Reply::AddrTypeNotSupported,
+            _ => Reply::GeneralFailure,
+        }
+    }
+}
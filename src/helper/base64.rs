@@ -0,0 +1,93 @@
+use define::ErrCode;
+use define::ErrCode::*;
+
+const STANDARD:&[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const URL_SAFE:&[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+pub fn encode(input:&[u8], url_safe:bool, pad:bool) -> String {
+    let table = if url_safe { URL_SAFE } else { STANDARD };
+    let mut out = String::with_capacity((input.len() + 2) / 3 * 4);
+    for chunk in input.chunks(3) {
+        let b0 = chunk[0] as u32;
+        let b1 = if chunk.len() > 1 { chunk[1] as u32 } else { 0 };
+        let b2 = if chunk.len() > 2 { chunk[2] as u32 } else { 0 };
+        let n = (b0 << 16) | (b1 << 8) | b2;
+        out.push(table[(n >> 18) as usize & 63] as char);
+        out.push(table[(n >> 12) as usize & 63] as char);
+        if chunk.len() > 1 {
+            out.push(table[(n >> 6) as usize & 63] as char);
+        } else if pad {
+            out.push('=');
+        }
+        if chunk.len() > 2 {
+            out.push(table[n as usize & 63] as char);
+        } else if pad {
+            out.push('=');
+        }
+    }
+    out
+}
+
+///accept both the standard and the url safe alphabet, the padding and the whitespace are optional
+pub fn decode(input:&str) -> Result<Vec<u8>, ErrCode> {
+    let mut out = Vec::with_capacity(input.len() * 3 / 4);
+    let mut n:u32 = 0;
+    let mut bits = 0;
+    for c in input.bytes() {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            b'=' => break,
+            b' ' | b'\t' | b'\r' | b'\n' => continue,
+            _ => return Err(UrlErr),
+        };
+        n = (n << 6) | v as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((n >> bits) as u8);
+            n &= (1 << bits) - 1;
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_pads_and_alphabets() {
+        assert_eq!(encode(b"", false, true), "");
+        assert_eq!(encode(b"f", false, true), "Zg==");
+        assert_eq!(encode(b"fo", false, true), "Zm8=");
+        assert_eq!(encode(b"foo", false, true), "Zm9v");
+        assert_eq!(encode(b"fo", false, false), "Zm8");
+        assert_eq!(encode(&[0xfb, 0xff], false, true), "+/8=");
+        assert_eq!(encode(&[0xfb, 0xff], true, false), "-_8");
+    }
+
+    #[test]
+    fn decode_accepts_both_alphabets() {
+        assert_eq!(decode("Zm9vYmFy").unwrap(), b"foobar".to_vec());
+        assert_eq!(decode("Zm8=").unwrap(), b"fo".to_vec());
+        assert_eq!(decode("Zm8").unwrap(), b"fo".to_vec());
+        assert_eq!(decode("+/8=").unwrap(), vec![0xfb, 0xff]);
+        assert_eq!(decode("-_8").unwrap(), vec![0xfb, 0xff]);
+        assert_eq!(decode("Zm9v\r\nYmFy").unwrap(), b"foobar".to_vec());
+        assert_eq!(decode("Zm9v!"), Err(UrlErr));
+    }
+
+    #[test]
+    fn round_trip() {
+        let data:Vec<u8> = (0..=255).collect();
+        for len in 0..data.len() {
+            for &(url_safe, pad) in &[(false, true), (true, false)] {
+                assert_eq!(decode(&encode(&data[..len], url_safe, pad)).unwrap(), data[..len].to_vec());
+            }
+        }
+    }
+}
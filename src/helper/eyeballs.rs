@@ -0,0 +1,69 @@
+//! happy eyeballs, RFC 8305: the addresses are tried in parallel, each one
+//! 250ms after the previous, or at once when the previous failed.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+///connection attempt delay of RFC 8305
+const ATTEMPT_DELAY:u64 = 250;
+
+///ipv6 first, then the families alternate
+pub fn sort_addrs(ips:&[IpAddr]) -> Vec<IpAddr> {
+    let v6:Vec<IpAddr> = ips.iter().filter(|ip| ip.is_ipv6()).cloned().collect();
+    let v4:Vec<IpAddr> = ips.iter().filter(|ip| ip.is_ipv4()).cloned().collect();
+    let mut sorted = Vec::with_capacity(ips.len());
+    for i in 0..v6.len().max(v4.len()) {
+        sorted.extend(v6.get(i));
+        sorted.extend(v4.get(i));
+    }
+    sorted
+}
+
+///the first established connection wins, the later ones are closed.
+///the error of the last attempt is returned when all of them fail
+pub fn happy_eyeballs(ips:&[IpAddr], port:u16, time_out:Duration) -> io::Result<TcpStream> {
+    let addrs:Vec<SocketAddr> = sort_addrs(ips).into_iter().map(|ip| SocketAddr::new(ip, port)).collect();
+    if addrs.len() == 1 {
+        return TcpStream::connect_timeout(&addrs[0], time_out);
+    }
+    let deadline = Instant::now() + time_out;
+    let (sx, rx) = channel();
+    let mut next = 0;
+    let mut pending = 0;
+    let mut last_err = io::Error::new(io::ErrorKind::InvalidInput, "no address to connect");
+    loop {
+        if pending == 0 && next >= addrs.len() {
+            return Err(last_err);
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "connect timed out"));
+        }
+        //the first attempt, or the previous one failed or is slow
+        if next < addrs.len() {
+            let addr = addrs[next];
+            let sx = sx.clone();
+            let remain = deadline - now;
+            let _ = thread::spawn(move || {
+                //the receiver is gone once another attempt won, the stream is dropped
+                let _ = sx.send((addr, TcpStream::connect_timeout(&addr, remain)));
+            });
+            next += 1;
+            pending += 1;
+        }
+        let wait = Duration::from_millis(ATTEMPT_DELAY).min(deadline - now);
+        match rx.recv_timeout(wait) {
+            Ok((_, Ok(stream))) => return Ok(stream),
+            Ok((addr, Err(e))) => {
+                info!("connect {} failed, {}", addr, e);
+                pending -= 1;
+                last_err = e;
+            },
+            Err(RecvTimeoutError::Timeout) => {},
+            Err(RecvTimeoutError::Disconnected) => return Err(last_err),
+        }
+    }
+}
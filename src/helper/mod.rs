@@ -11,18 +11,32 @@ extern crate dns_lookup;
 use dns_lookup::lookup_host;
 
 use std::ops::Not;
+use std::ffi::CStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-pub fn get_ip_addr(hostname:&str) -> Result<Ipv4Addr, ErrCode> {
-    let ips: Vec<IpAddr> = lookup_host(hostname).or(Err(UrlErr))?;
-    let address = ips.into_iter().next().ok_or(UrlErr)?;
-    match address {
-        IpAddr::V4(addr) => {
-            Ok(addr)
-        },
-        _ => {
-            Err(UrlErr)
+extern crate libc;
+
+mod tracker;
+pub use self::tracker::Tracker;
+
+pub mod base64;
+
+mod eyeballs;
+pub use self::eyeballs::happy_eyeballs;
+
+///all the A and AAAA records, without duplicates
+pub fn lookup(hostname:&str) -> Result<Vec<IpAddr>, ErrCode> {
+    let mut ips: Vec<IpAddr> = Vec::new();
+    for ip in lookup_host(hostname).or(Err(UrlErr))? {
+        if !ips.contains(&ip) {
+            ips.push(ip);
         }
     }
+    if ips.is_empty() {
+        return Err(UrlErr);
+    }
+    Ok(ips)
 }
 
 pub fn encode<T: AsRef<[u8]>>(input:T) -> BytesMut {
@@ -33,3 +47,90 @@ pub fn encode<T: AsRef<[u8]>>(input:T) -> BytesMut {
     }
     buf
 }
+
+pub fn hostname() -> Option<String> {
+    let mut buf = vec![0 as libc::c_char; 256];
+    let rst = unsafe { libc::gethostname(buf.as_mut_ptr(), buf.len()) };
+    if rst != 0 {
+        return None;
+    }
+    let name = unsafe { CStr::from_ptr(buf.as_ptr()) };
+    name.to_str().ok().map(|s| s.to_string())
+}
+
+///not for crypto, spreads the load and the delays
+pub fn rand_u64() -> u64 {
+    const GAMMA:u64 = 0x9E3779B97F4A7C15;
+    static STATE: AtomicU64 = AtomicU64::new(0);
+    if STATE.load(Ordering::Relaxed) == 0 {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+        let seed = (now.as_secs() << 30) ^ (now.subsec_nanos() as u64) | 1;
+        let _ = STATE.compare_exchange(0, seed, Ordering::Relaxed, Ordering::Relaxed);
+    }
+    //splitmix64
+    let mut z = STATE.fetch_add(GAMMA, Ordering::Relaxed).wrapping_add(GAMMA);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+///utc with milliseconds, like 2018-06-01T08:30:00.250Z
+pub fn rfc3339(time:SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    let secs = since.as_secs();
+    //the civil date of the days since 1970-01-01, in eras of 400 years from 0000-03-01
+    let z = secs / 86400 + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    let rem = secs % 86400;
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", year, month, day, rem / 3600, rem / 60 % 60, rem % 60, since.subsec_millis())
+}
+
+///ip/len, a bare ip is a host route
+pub fn parse_cidr(value:&str) -> Option<(IpAddr, u8)> {
+    let mut parts = value.splitn(2, '/');
+    let ip:IpAddr = parts.next()?.parse().ok()?;
+    let max = if ip.is_ipv4() { 32 } else { 128 };
+    let len = match parts.next() {
+        Some(len) => len.parse().ok()?,
+        None => max,
+    };
+    if len > max {
+        return None;
+    }
+    Some((ip, len))
+}
+
+pub fn cidr_contains(net:&IpAddr, len:u8, ip:&IpAddr) -> bool {
+    match (*net, *ip) {
+        (IpAddr::V4(net), IpAddr::V4(ip)) => {
+            let mask = if len == 0 { 0 } else { u32::max_value() << (32 - len) };
+            u32::from(net) & mask == u32::from(ip) & mask
+        },
+        (IpAddr::V6(net), IpAddr::V6(ip)) => {
+            let mask = if len == 0 { 0 } else { u128::max_value() << (128 - len) };
+            u128::from(net) & mask == u128::from(ip) & mask
+        },
+        _ => false,
+    }
+}
+
+///low-high, a single port is a range of one
+pub fn parse_port_range(value:&str) -> Option<(u16, u16)> {
+    let mut parts = value.splitn(2, '-');
+    let low:u16 = parts.next()?.trim().parse().ok()?;
+    let high:u16 = match parts.next() {
+        Some(high) => high.trim().parse().ok()?,
+        None => low,
+    };
+    if low > high {
+        return None;
+    }
+    Some((low, high))
+}
@@ -0,0 +1,80 @@
+use define::ErrCode;
+use define::ErrCode::*;
+
+use std::collections::BTreeMap;
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+struct Inner {
+    next_id: u64,
+    map: BTreeMap<u64, TcpStream>,
+}
+
+///the active tunnels, used to drain them on shutdown
+#[derive(Debug, Clone)]
+pub struct Tracker {
+    inner: Arc<Mutex<Inner>>,
+}
+
+///removes the tunnel from the tracker when the tunnel thread finishes
+pub struct Guard {
+    id: u64,
+    tracker: Tracker,
+}
+
+impl Drop for Guard {
+
+    fn drop(&mut self) {
+        if let Ok(mut inner) = self.tracker.inner.lock() {
+            let _ = inner.map.remove(&self.id);
+        }
+    }
+}
+
+impl Tracker {
+
+    pub fn new() -> Self {
+        let inner = Inner {next_id: 0, map: BTreeMap::new()};
+        Tracker {
+            inner: Arc::new(Mutex::new(inner)),
+        }
+    }
+
+    pub fn register(&self, stream:&TcpStream) -> Result<Guard, ErrCode> {
+        let stream = stream.try_clone().or(Err(SocketErr))?;
+        let mut inner = self.inner.lock().or(Err(LockErr))?;
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.map.insert(id, stream);
+        Ok(Guard {
+            id: id,
+            tracker: self.clone(),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().map(|inner| inner.map.len()).unwrap_or(0)
+    }
+
+    ///wait for the tunnels to finish until the deadline, then close the rest.
+    ///return the count of the force-closed tunnels.
+    pub fn drain(&self, time_out:Duration) -> usize {
+        let deadline = Instant::now() + time_out;
+        while self.len() > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(100));
+        }
+        let mut inner = match self.inner.lock() {
+            Ok(inner) => inner,
+            Err(_) => return 0,
+        };
+        let count = inner.map.len();
+        for (_, stream) in inner.map.iter() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        inner.map.clear();
+        count
+    }
+}
@@ -4,6 +4,14 @@ extern crate serde_json;
 
 pub mod local;
 pub mod server;
+pub mod signal;
+pub mod config;
+pub mod cli;
+pub mod sip002;
+pub mod admin;
+pub mod metrics;
+pub mod access;
+pub mod limit;
 
 pub mod define;
 pub use define::*;
@@ -0,0 +1,277 @@
+//! bandwidth limits of the tunnels, token buckets of bytes per second.
+//! a tunnel takes from the bucket of its connection, its user and the process,
+//! and waits for the slowest of them. the buckets may go into debt, so a read
+//! larger than the burst passes and the next one waits longer.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use config::{LimitConfig, RateLimit};
+
+#[derive(Debug)]
+pub struct Bucket {
+    rate: f64, //bytes per second
+    burst: f64,
+    state: Mutex<(f64, Instant)>, //the tokens at the time
+}
+
+impl Bucket {
+
+    ///none if the rate is unlimited
+    pub fn new(rate:u64, burst:u64) -> Option<Arc<Bucket>> {
+        if rate == 0 {
+            return None;
+        }
+        let burst = if burst == 0 { rate } else { burst } as f64;
+        Some(Arc::new(Bucket {
+            rate: rate as f64,
+            burst: burst,
+            state: Mutex::new((burst, Instant::now())),
+        }))
+    }
+
+    ///take the bytes, the time to wait until the tokens are back
+    pub fn take(&self, size:usize) -> Duration {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => return Duration::from_secs(0),
+        };
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.1);
+        let tokens = state.0 + (elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9) * self.rate;
+        let tokens = tokens.min(self.burst) - size as f64;
+        *state = (tokens, now);
+        if tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            let wait = -tokens / self.rate;
+            Duration::new(wait as u64, ((wait - wait.floor()) * 1e9) as u32)
+        }
+    }
+}
+
+///the buckets of one tunnel, shared by its two threads
+#[derive(Debug, Clone, Default)]
+pub struct Throttle {
+    up: Vec<Arc<Bucket>>,
+    down: Vec<Arc<Bucket>>,
+    user: Option<Arc<Buckets>>, //keeps the buckets of the user in the limiter
+}
+
+impl Throttle {
+
+    pub fn up(&self, size:usize) {
+        wait(&self.up, size);
+    }
+
+    pub fn down(&self, size:usize) {
+        wait(&self.down, size);
+    }
+}
+
+fn wait(buckets:&[Arc<Bucket>], size:usize) {
+    let delay = buckets.iter().map(|bucket| bucket.take(size)).max();
+    if let Some(delay) = delay {
+        if delay > Duration::from_secs(0) {
+            thread::sleep(delay);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    up: Option<Arc<Bucket>>,
+    down: Option<Arc<Bucket>>,
+}
+
+impl Buckets {
+
+    fn new(limit:&RateLimit) -> Self {
+        Buckets {
+            up: Bucket::new(limit.up, limit.burst),
+            down: Bucket::new(limit.down, limit.burst),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    cfg: LimitConfig,
+    global: Buckets,
+    users: BTreeMap<String, Weak<Buckets>>, //dropped with the last tunnel of the user
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Limiter {
+    inner: Arc<RwLock<Inner>>,
+}
+
+impl Limiter {
+
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    ///new buckets if the limits changed, the open tunnels keep the old ones
+    pub fn set_config(&self, cfg:LimitConfig) {
+        if let Ok(mut inner) = self.inner.write() {
+            if inner.cfg == cfg {
+                return;
+            }
+            info!("bandwidth limits: connection {:?}, user {:?}, global {:?}", cfg.connection, cfg.user, cfg.global);
+            *inner = Inner {
+                cfg: cfg,
+                global: Buckets::new(&cfg.global),
+                users: BTreeMap::new(),
+            };
+        }
+    }
+
+    ///the throttle of a new tunnel, the user buckets are shared by its connections
+    pub fn open(&self, user:Option<&str>) -> Throttle {
+        let mut throttle:Throttle = Default::default();
+        let mut inner = match self.inner.write() {
+            Ok(inner) => inner,
+            Err(_) => return throttle,
+        };
+        let connection = Buckets::new(&inner.cfg.connection);
+        let cfg = inner.cfg.user;
+        let user = match user {
+            Some(user) if cfg.up > 0 || cfg.down > 0 => {
+                inner.users.retain(|_, buckets| buckets.upgrade().is_some());
+                let buckets = inner.users.get(user).and_then(|buckets| buckets.upgrade()).unwrap_or_else(|| Arc::new(Buckets::new(&cfg)));
+                let _ = inner.users.insert(user.to_string(), Arc::downgrade(&buckets));
+                Some(buckets)
+            },
+            _ => None,
+        };
+        {
+            let mut push = |buckets:&Buckets| {
+                throttle.up.extend(buckets.up.clone());
+                throttle.down.extend(buckets.down.clone());
+            };
+            push(&connection);
+            if let Some(ref user) = user {
+                push(user);
+            }
+            push(&inner.global);
+        }
+        throttle.user = user;
+        throttle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(wait:Duration) -> f64 {
+        wait.as_secs() as f64 + wait.subsec_nanos() as f64 / 1e9
+    }
+
+    ///the tokens at a time in the past
+    fn set(bucket:&Bucket, tokens:f64, ago:u64) {
+        *bucket.state.lock().unwrap() = (tokens, Instant::now().checked_sub(Duration::from_millis(ago)).unwrap());
+    }
+
+    fn tokens(bucket:&Bucket) -> f64 {
+        bucket.state.lock().unwrap().0
+    }
+
+    #[test]
+    fn bucket_defaults() {
+        assert!(Bucket::new(0, 1000).is_none());
+        let bucket = Bucket::new(1000, 0).unwrap();
+        assert_eq!((bucket.rate, bucket.burst), (1000.0, 1000.0));
+        let bucket = Bucket::new(1000, 300).unwrap();
+        assert_eq!(bucket.burst, 300.0);
+        //starts full
+        assert_eq!(tokens(&bucket), 300.0);
+    }
+
+    #[test]
+    fn take_and_debt() {
+        let bucket = Bucket::new(1000, 0).unwrap();
+        assert_eq!(bucket.take(400), Duration::from_secs(0));
+        assert_eq!(bucket.take(600), Duration::from_secs(0));
+        //larger than the burst, passes and waits for the debt
+        set(&bucket, 0.0, 0);
+        let wait = secs(bucket.take(2500));
+        assert!(wait > 2.49 && wait <= 2.5, "{}", wait);
+        let wait = secs(bucket.take(500));
+        assert!(wait > 2.99 && wait <= 3.0, "{}", wait);
+    }
+
+    #[test]
+    fn refill_up_to_the_burst() {
+        let bucket = Bucket::new(1000, 0).unwrap();
+        //half a second pays half of the debt
+        set(&bucket, -1000.0, 500);
+        let wait = secs(bucket.take(0));
+        assert!(wait > 0.49 && wait <= 0.5, "{}", wait);
+        //an idle bucket holds the burst, not more
+        set(&bucket, 0.0, 10_000);
+        assert_eq!(bucket.take(1000), Duration::from_secs(0));
+        let wait = secs(bucket.take(100));
+        assert!(wait > 0.099 && wait <= 0.1, "{}", wait);
+    }
+
+    fn limits(connection:u64, user:u64, global:u64) -> LimitConfig {
+        let rate = |rate:u64| RateLimit {up: rate, down: rate * 2, burst: 0};
+        LimitConfig {
+            connection: rate(connection),
+            user: rate(user),
+            global: rate(global),
+        }
+    }
+
+    #[test]
+    fn unlimited() {
+        let limiter = Limiter::new();
+        let throttle = limiter.open(Some("alice"));
+        assert!(throttle.up.is_empty() && throttle.down.is_empty() && throttle.user.is_none());
+    }
+
+    #[test]
+    fn buckets_of_a_user_are_shared() {
+        let limiter = Limiter::new();
+        limiter.set_config(limits(100, 1000, 10000));
+        let first = limiter.open(Some("alice"));
+        let second = limiter.open(Some("alice"));
+        let other = limiter.open(Some("bob"));
+        let anonymous = limiter.open(None);
+        //the connection, the user and the global bucket
+        assert_eq!((first.up.len(), first.down.len(), anonymous.up.len()), (3, 3, 2));
+        assert_eq!((first.up[0].rate, first.up[1].rate, first.up[2].rate), (100.0, 1000.0, 10000.0));
+        assert_eq!(first.down[1].rate, 2000.0);
+        assert!(!Arc::ptr_eq(&first.up[0], &second.up[0]));
+        assert!(Arc::ptr_eq(&first.up[1], &second.up[1]));
+        assert!(Arc::ptr_eq(&first.down[1], &second.down[1]));
+        assert!(!Arc::ptr_eq(&first.up[1], &other.up[1]));
+        assert!(Arc::ptr_eq(&first.up[2], &other.up[2]) && Arc::ptr_eq(&first.up[2], &anonymous.up[1]));
+        //the user buckets go with the last tunnel
+        let bucket = Arc::downgrade(&first.up[1]);
+        drop(first);
+        drop(second);
+        assert!(bucket.upgrade().is_none());
+        let _carol = limiter.open(Some("carol"));
+        assert_eq!(limiter.inner.read().unwrap().users.keys().collect::<Vec<_>>(), vec!["bob", "carol"]);
+    }
+
+    #[test]
+    fn new_limits_new_buckets() {
+        let limiter = Limiter::new();
+        limiter.set_config(limits(0, 0, 1000));
+        let before = limiter.open(None);
+        limiter.set_config(limits(0, 0, 1000));
+        assert!(Arc::ptr_eq(&before.up[0], &limiter.open(None).up[0]));
+        limiter.set_config(limits(0, 0, 2000));
+        let after = limiter.open(None);
+        assert!(!Arc::ptr_eq(&before.up[0], &after.up[0]));
+        assert_eq!(after.up[0].rate, 2000.0);
+        //the open tunnels keep the old ones
+        assert_eq!(before.up[0].rate, 1000.0);
+    }
+}
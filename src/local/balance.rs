@@ -0,0 +1,203 @@
+use define::ErrCode;
+use define::ErrCode::*;
+
+use std::io;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use config::{ServerConfig, Strategy};
+use helper;
+
+///one server of sslocal
+#[derive(Debug)]
+pub struct Upstream {
+    pub server: String,
+    pub server_port: u32,
+    active: AtomicUsize,
+    latency: AtomicU64, //average connect time in ms, 0 is unknown
+    up: AtomicBool, //health check state
+    fails: AtomicUsize, //consecutive failed probes
+    successes: AtomicUsize, //consecutive successful probes
+    check_latency: AtomicU64, //last tunnelled check in ms, 0 is unknown
+}
+
+///holds a connection of the upstream until dropped
+pub struct Lease {
+    upstream: Arc<Upstream>,
+}
+
+impl Drop for Lease {
+
+    fn drop(&mut self) {
+        self.upstream.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Upstream {
+
+    pub fn new(server:&str, server_port:u32) -> Self {
+        Upstream {
+            server: server.to_string(),
+            server_port: server_port,
+            active: AtomicUsize::new(0),
+            latency: AtomicU64::new(0),
+            up: AtomicBool::new(true),
+            fails: AtomicUsize::new(0),
+            successes: AtomicUsize::new(0),
+            check_latency: AtomicU64::new(0),
+        }
+    }
+
+    pub fn addr(&self) -> String {
+        format!("{}:{}", self.server, self.server_port)
+    }
+
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    pub fn latency(&self) -> Option<u64> {
+        match self.latency.load(Ordering::SeqCst) {
+            0 => None,
+            ms => Some(ms),
+        }
+    }
+
+    ///moving average of the connect time
+    pub fn record_latency(&self, ms:u64) {
+        let ms = if ms == 0 { 1 } else { ms };
+        let old = self.latency.load(Ordering::SeqCst);
+        let new = if old == 0 { ms } else { (old * 7 + ms) / 8 };
+        self.latency.store(new, Ordering::SeqCst);
+    }
+
+    pub fn is_up(&self) -> bool {
+        self.up.load(Ordering::SeqCst)
+    }
+
+    pub fn fails(&self) -> usize {
+        self.fails.load(Ordering::SeqCst)
+    }
+
+    pub fn check_latency(&self) -> Option<u64> {
+        match self.check_latency.load(Ordering::SeqCst) {
+            0 => None,
+            ms => Some(ms),
+        }
+    }
+
+    pub fn set_check_latency(&self, ms:u64) {
+        self.check_latency.store(if ms == 0 { 1 } else { ms }, Ordering::SeqCst);
+    }
+
+    ///count the probe, return the new state if it changed
+    pub fn report(&self, ok:bool, fall:u64, rise:u64) -> Option<bool> {
+        if ok {
+            self.fails.store(0, Ordering::SeqCst);
+            let successes = self.successes.fetch_add(1, Ordering::SeqCst) + 1;
+            if !self.is_up() && successes as u64 >= rise {
+                self.up.store(true, Ordering::SeqCst);
+                return Some(true);
+            }
+        } else {
+            self.successes.store(0, Ordering::SeqCst);
+            let fails = self.fails.fetch_add(1, Ordering::SeqCst) + 1;
+            if self.is_up() && fails as u64 >= fall {
+                self.up.store(false, Ordering::SeqCst);
+                return Some(false);
+            }
+        }
+        None
+    }
+
+    pub fn connect(upstream:&Arc<Upstream>, time_out:Duration) -> io::Result<(TcpStream, Lease)> {
+        let start = Instant::now();
+        let rst = (upstream.server.as_str(), upstream.server_port as u16).to_socket_addrs()
+            .and_then(|mut addrs| addrs.next().ok_or(io::Error::new(io::ErrorKind::AddrNotAvailable, "no address")))
+            .and_then(|addr| TcpStream::connect_timeout(&addr, time_out));
+        match rst {
+            Ok(stream) => {
+                let elapsed = start.elapsed();
+                upstream.record_latency(elapsed.as_secs() * 1000 + elapsed.subsec_millis() as u64);
+                upstream.active.fetch_add(1, Ordering::SeqCst);
+                Ok((stream, Lease {upstream: upstream.clone()}))
+            },
+            Err(e) => {
+                //a failed server goes to the end of lowest_latency
+                upstream.record_latency(time_out.as_secs() * 1000);
+                Err(e)
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    strategy: RwLock<Strategy>,
+    upstreams: RwLock<Vec<Arc<Upstream>>>,
+    next: AtomicUsize,
+}
+
+///picks the server of a new connection, the others are tried in order on failure
+#[derive(Debug, Clone)]
+pub struct Balancer {
+    inner: Arc<Inner>,
+}
+
+impl Balancer {
+
+    pub fn new(servers:&[ServerConfig], strategy:Strategy) -> Self {
+        let upstreams = servers.iter().map(|s| Arc::new(Upstream::new(&s.server, s.server_port))).collect();
+        let inner = Inner {
+            strategy: RwLock::new(strategy),
+            upstreams: RwLock::new(upstreams),
+            next: AtomicUsize::new(0),
+        };
+        Balancer {
+            inner: Arc::new(inner),
+        }
+    }
+
+    ///replace the servers on reload, the known ones keep their stats
+    pub fn set_servers(&self, servers:&[ServerConfig], strategy:Strategy) -> Result<(), ErrCode> {
+        let mut upstreams = self.inner.upstreams.write().or(Err(LockErr))?;
+        let new_upstreams = servers.iter().map(|s| {
+            upstreams.iter()
+                .find(|u| u.server == s.server && u.server_port == s.server_port)
+                .map(|u| u.clone())
+                .unwrap_or_else(|| Arc::new(Upstream::new(&s.server, s.server_port)))
+        }).collect();
+        *upstreams = new_upstreams;
+        *self.inner.strategy.write().or(Err(LockErr))? = strategy;
+        Ok(())
+    }
+
+    pub fn upstreams(&self) -> Vec<Arc<Upstream>> {
+        self.inner.upstreams.read().map(|u| u.clone()).unwrap_or(Vec::new())
+    }
+
+    ///all the servers, the preferred first, the down ones are the last resort
+    pub fn candidates(&self) -> Vec<Arc<Upstream>> {
+        let mut list = self.upstreams();
+        if list.is_empty() {
+            return list;
+        }
+        let strategy = self.inner.strategy.read().map(|s| *s).unwrap_or(Strategy::RoundRobin);
+        let len = list.len();
+        let start = match strategy {
+            Strategy::Random => helper::rand_u64() as usize % len,
+            _ => self.inner.next.fetch_add(1, Ordering::SeqCst) % len,
+        };
+        //rotate first, so the ties of the sort below take turns
+        list.rotate_left(start);
+        match strategy {
+            Strategy::LeastConnections => list.sort_by_key(|u| u.active()),
+            Strategy::LowestLatency => list.sort_by_key(|u| u.latency().unwrap_or(0)),
+            _ => {},
+        }
+        list.sort_by_key(|u| !u.is_up());
+        list
+    }
+}
@@ -0,0 +1,271 @@
+//! gfwlist, a base64 encoded AdBlock Plus list of the hosts to proxy.
+//!
+//! ```text
+//! ||example.com          the domain and its subdomains
+//! |http://example.com/   the urls starting with it
+//! @@||example.org        an exception, connect directly
+//! /^https?:\/\/[^\/]+example\.net/   a regex of the url
+//! .example.edu           a part of the url, * is a wildcard
+//! ```
+//!
+//! only the host and the port of a connection are known, the url is built
+//! as http://host/ or https://host/ for port 443.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Read;
+
+use serde_json::{Map, Value};
+
+extern crate regex;
+use self::regex::Regex;
+
+use helper::base64;
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum Verdict {
+    Listed, //should go through the server
+    Excepted, //an @@ exception, should connect directly
+    Unlisted,
+}
+
+#[derive(Debug, Default)]
+struct Patterns {
+    domains: HashSet<String>, //||domain
+    hosts: HashSet<String>, //|http://host
+    keywords: Vec<String>, //url parts with * wildcards
+    regexes: Vec<Regex>,
+}
+
+impl Patterns {
+
+    fn is_match(&self, host:&str, url:&str) -> bool {
+        if self.hosts.contains(host) {
+            return true;
+        }
+        //the host itself and every parent domain
+        let mut domain = host;
+        loop {
+            if self.domains.contains(domain) {
+                return true;
+            }
+            match domain.find('.') {
+                Some(pos) => domain = &domain[pos + 1..],
+                None => break,
+            }
+        }
+        if self.keywords.iter().any(|k| wildcard_match(k, url)) {
+            return true;
+        }
+        self.regexes.iter().any(|re| re.is_match(url))
+    }
+
+    fn to_json(&self) -> Value {
+        let mut domains = Map::new();
+        for domain in self.domains.iter() {
+            domains.insert(domain.clone(), Value::from(1));
+        }
+        let mut hosts = Map::new();
+        for host in self.hosts.iter() {
+            hosts.insert(host.clone(), Value::from(1));
+        }
+        let mut map = Map::new();
+        map.insert("domains".to_string(), Value::Object(domains));
+        map.insert("hosts".to_string(), Value::Object(hosts));
+        map.insert("keywords".to_string(), Value::from(self.keywords.clone()));
+        map.insert("regexes".to_string(), Value::from(self.regexes.iter().map(|re| re.as_str().to_string()).collect::<Vec<String>>()));
+        Value::Object(map)
+    }
+
+    fn add(&mut self, rule:&str) -> Result<(), String> {
+        //drop the filter options
+        let rule = match rule.find('$') {
+            Some(pos) if !rule.starts_with('/') => &rule[..pos],
+            _ => rule,
+        };
+        if rule.starts_with("||") {
+            let host = host_of(&rule[2..]);
+            if host.contains('*') {
+                self.keywords.push(host);
+            } else if !host.is_empty() {
+                self.domains.insert(host);
+            }
+        } else if rule.starts_with('|') {
+            let rest = &rule[1..];
+            let rest = match rest.find("://") {
+                Some(pos) => &rest[pos + 3..],
+                None => rest,
+            };
+            let host = host_of(rest);
+            if host.contains('*') {
+                self.keywords.push(host);
+            } else if !host.is_empty() {
+                self.hosts.insert(host);
+            }
+        } else if rule.starts_with('/') && rule.ends_with('/') && rule.len() > 2 {
+            //javascript escapes the slashes, the regex crate does not accept \/
+            let pattern = rule[1..rule.len() - 1].replace("\\/", "/");
+            let re = Regex::new(&pattern).map_err(|e| format!("invalid regex, {}", e))?;
+            self.regexes.push(re);
+        } else if !rule.is_empty() {
+            self.keywords.push(rule.trim_end_matches('|').to_lowercase());
+        }
+        Ok(())
+    }
+}
+
+///the host part of domain/path, domain:port or domain^
+fn host_of(rest:&str) -> String {
+    let end = rest.find(|c| c == '/' || c == ':' || c == '^' || c == '|').unwrap_or(rest.len());
+    rest[..end].trim_start_matches('.').to_lowercase()
+}
+
+///substring match, * matches anything
+fn wildcard_match(pattern:&str, text:&str) -> bool {
+    let mut pos = 0;
+    for part in pattern.split('*') {
+        if part.is_empty() {
+            continue;
+        }
+        match text[pos..].find(part) {
+            Some(found) => pos += found + part.len(),
+            None => return false,
+        }
+    }
+    true
+}
+
+#[derive(Debug, Default)]
+pub struct GfwList {
+    block: Patterns,
+    allow: Patterns,
+}
+
+impl GfwList {
+
+    ///the list text, plain or base64 encoded, the errors are prefixed with the name
+    pub fn parse(name:&str, text:&str) -> Result<GfwList, Vec<String>> {
+        let decoded;
+        let text = if text.contains("[AutoProxy") || text.contains("||") {
+            text
+        } else {
+            let bytes = base64::decode(text).map_err(|_| vec![format!("{}: invalid base64", name)])?;
+            decoded = String::from_utf8(bytes).map_err(|_| vec![format!("{}: invalid utf-8", name)])?;
+            &decoded
+        };
+        let mut list:GfwList = Default::default();
+        let mut errors = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('!') || line.starts_with('[') {
+                continue;
+            }
+            let rst = if line.starts_with("@@") {
+                list.allow.add(&line[2..])
+            } else {
+                list.block.add(line)
+            };
+            if let Err(msg) = rst {
+                errors.push(format!("{}:{}: {}", name, i + 1, msg));
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(list)
+    }
+
+    pub fn load(path:&str) -> Result<GfwList, Vec<String>> {
+        let mut text = String::new();
+        let _ = File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut text))
+            .map_err(|e| vec![format!("{}: can not read, {}", path, e)])?;
+        Self::parse(path, &text)
+    }
+
+    ///the patterns for the pac file, {block: {domains, hosts, keywords, regexes}, allow: {..}}
+    pub fn to_json(&self) -> Value {
+        let mut map = Map::new();
+        map.insert("block".to_string(), self.block.to_json());
+        map.insert("allow".to_string(), self.allow.to_json());
+        Value::Object(map)
+    }
+
+    pub fn check(&self, host:&str, port:u16) -> Verdict {
+        let host = host.trim_end_matches('.').to_lowercase();
+        let scheme = if port == 443 { "https" } else { "http" };
+        let url = format!("{}://{}/", scheme, host);
+        if self.allow.is_match(&host, &url) {
+            return Verdict::Excepted;
+        }
+        if self.block.is_match(&host, &url) {
+            return Verdict::Listed;
+        }
+        Verdict::Unlisted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIST:&str = "[AutoProxy 0.2.9]
+! a comment
+||example.com
+|http://plain.example.org/path
+|https://*.wild.example/
+@@||direct.example.com
+/^https?:\\/\\/[^\\/]+regex\\.example\\.net/
+.keyword.example.edu
+||options.example$third-party
+";
+
+    #[test]
+    fn domains_and_exceptions() {
+        let list = GfwList::parse("test", LIST).unwrap();
+        assert_eq!(list.check("example.com", 80), Verdict::Listed);
+        assert_eq!(list.check("www.Example.COM.", 443), Verdict::Listed);
+        assert_eq!(list.check("notexample.com", 80), Verdict::Unlisted);
+        assert_eq!(list.check("direct.example.com", 80), Verdict::Excepted);
+        assert_eq!(list.check("a.direct.example.com", 80), Verdict::Excepted);
+        assert_eq!(list.check("options.example", 80), Verdict::Listed);
+    }
+
+    #[test]
+    fn hosts_keywords_and_regexes() {
+        let list = GfwList::parse("test", LIST).unwrap();
+        assert_eq!(list.check("plain.example.org", 80), Verdict::Listed);
+        assert_eq!(list.check("sub.plain.example.org", 80), Verdict::Unlisted);
+        assert_eq!(list.check("a.wild.example", 443), Verdict::Listed);
+        assert_eq!(list.check("www.keyword.example.edu", 80), Verdict::Listed);
+        assert_eq!(list.check("cdn.regex.example.net", 443), Verdict::Listed);
+        assert_eq!(list.check("regex.example.com.cn", 80), Verdict::Unlisted);
+    }
+
+    #[test]
+    fn base64_list() {
+        let encoded = base64::encode(LIST.as_bytes(), false, true);
+        //wrapped at 64 columns like the published list
+        let wrapped:Vec<String> = encoded.as_bytes().chunks(64).map(|c| String::from_utf8(c.to_vec()).unwrap()).collect();
+        let list = GfwList::parse("test", &wrapped.join("\n")).unwrap();
+        assert_eq!(list.check("example.com", 80), Verdict::Listed);
+        assert_eq!(list.check("direct.example.com", 80), Verdict::Excepted);
+    }
+
+    #[test]
+    fn errors_have_the_line() {
+        let errors = GfwList::parse("gfw.txt", "||ok.example\n/(/\n").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("gfw.txt:2: invalid regex"), "{}", errors[0]);
+        assert_eq!(GfwList::parse("gfw.b64", "not base64!").unwrap_err(), vec!["gfw.b64: invalid base64".to_string()]);
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match("a*c", "xabyc"));
+        assert!(wildcard_match("*abc*", "abc"));
+        assert!(!wildcard_match("a*c", "ca"));
+        assert_eq!(host_of(".Example.com:8080/path"), "example.com");
+        assert_eq!(host_of("example.com^"), "example.com");
+    }
+}
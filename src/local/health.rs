@@ -0,0 +1,169 @@
+use define::{ErrCode, Reply};
+use define::ErrCode::*;
+
+use std::io::{Read, Write};
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+extern crate byteorder;
+use byteorder::BigEndian;
+
+extern crate bytes;
+use bytes::{BytesMut, BufMut};
+
+use config;
+use config::HealthCheck;
+use helper::encode;
+use local::balance::{Balancer, Upstream};
+use metrics;
+
+#[derive(Debug)]
+struct Inner {
+    balancer: Balancer,
+    check: RwLock<Option<HealthCheck>>,
+    connect_status: AtomicBool,
+}
+
+///probes the servers in the background, marks them down and up
+#[derive(Debug, Clone)]
+pub struct HealthChecker {
+    inner: Arc<Inner>,
+}
+
+impl HealthChecker {
+
+    pub fn new(balancer:Balancer) -> Self {
+        let inner = Inner {
+            balancer: balancer,
+            check: RwLock::new(None),
+            connect_status: AtomicBool::new(false),
+        };
+        HealthChecker {
+            inner: Arc::new(inner),
+        }
+    }
+
+    ///none disables the probes, the servers keep their last state
+    pub fn set_config(&self, check:Option<HealthCheck>, connect_status:bool) -> Result<(), ErrCode> {
+        *self.inner.check.write().or(Err(LockErr))? = check;
+        self.inner.connect_status.store(connect_status, Ordering::SeqCst);
+        Ok(())
+    }
+
+    pub fn start(&self) {
+        let checker = self.clone();
+        let _ = thread::spawn(move || {
+            loop {
+                let check = checker.inner.check.read().map(|c| c.clone()).unwrap_or(None);
+                match check {
+                    Some(check) => {
+                        checker.round(&check);
+                        thread::sleep(Duration::from_secs(check.interval));
+                    },
+                    None => {
+                        thread::sleep(Duration::from_secs(1));
+                    },
+                }
+            }
+        });
+    }
+
+    ///probe all the servers at the same time
+    fn round(&self, check:&HealthCheck) {
+        let connect_status = self.inner.connect_status.load(Ordering::SeqCst);
+        let mut handles = Vec::new();
+        for upstream in self.inner.balancer.upstreams() {
+            let check = check.clone();
+            handles.push(thread::spawn(move || {
+                let rst = Self::probe(&upstream, &check, connect_status);
+                if let Err(ref msg) = rst {
+                    warn!("health check of {} failed, {}", upstream.addr(), msg);
+                }
+                match upstream.report(rst.is_ok(), check.fall, check.rise) {
+                    Some(true) => info!("server {} is up", upstream.addr()),
+                    Some(false) => warn!("server {} is down after {} failed checks", upstream.addr(), upstream.fails()),
+                    None => {},
+                }
+            }));
+        }
+        for handle in handles {
+            let _ = handle.join();
+        }
+        info!("health of the servers:\n{}", self.table());
+    }
+
+    ///tcp connect, then a http request to the target through the tunnel
+    fn probe(upstream:&Arc<Upstream>, check:&HealthCheck, connect_status:bool) -> Result<(), String> {
+        let time_out = Duration::from_secs(check.timeout);
+        let start = Instant::now();
+        let (mut stream, _lease) = Upstream::connect(upstream, time_out).map_err(|e| format!("connect, {}", e))?;
+        let target = match check.target {
+            Some(ref target) => target,
+            None => return Ok(()),
+        };
+        let (host, port, path) = config::parse_http_url(target).ok_or(format!("invalid target {}", target))?;
+        let _ = stream.set_read_timeout(Some(time_out));
+        let _ = stream.set_write_timeout(Some(time_out));
+
+        let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", path, host);
+        let mut buf = BytesMut::with_capacity(4 + host.len() + request.len());
+        buf.put_u8(3);
+        buf.put_u8(host.len() as u8);
+        buf.put_slice(host.as_bytes());
+        buf.put_u16::<BigEndian>(port);
+        buf.put_slice(request.as_bytes());
+        let _ = stream.write_all(&encode(&buf)).map_err(|e| format!("write, {}", e))?;
+
+        if connect_status {
+            let mut status = [0u8; 1];
+            let _ = stream.read_exact(&mut status).map_err(|e| format!("read the status, {}", e))?;
+            let rep = Reply::from_u8(encode(&status)[0]);
+            if rep != Reply::Succeeded {
+                return Err(format!("server can not reach {}:{}, {}", host, port, rep.description()));
+            }
+        }
+        let mut head = [0u8; 7];
+        let _ = stream.read_exact(&mut head).map_err(|e| format!("read the response, {}", e))?;
+        if &encode(&head)[..] != b"HTTP/1." {
+            return Err("the response is not http".to_string());
+        }
+        let elapsed = start.elapsed();
+        upstream.set_check_latency(elapsed.as_secs() * 1000 + elapsed.subsec_millis() as u64);
+        Ok(())
+    }
+
+    ///the current state of the servers, one per line
+    pub fn table(&self) -> String {
+        let mut lines = vec![format!("{:<32} {:<5} {:>6} {:>10} {:>8} {:>5}", "server", "state", "active", "connect_ms", "check_ms", "fails")];
+        for upstream in self.inner.balancer.upstreams() {
+            let ms = |v:Option<u64>| v.map(|ms| ms.to_string()).unwrap_or("-".to_string());
+            lines.push(format!("{:<32} {:<5} {:>6} {:>10} {:>8} {:>5}",
+                upstream.addr(),
+                if upstream.is_up() { "up" } else { "down" },
+                upstream.active(),
+                ms(upstream.latency()),
+                ms(upstream.check_latency()),
+                upstream.fails()));
+        }
+        lines.join("\n")
+    }
+
+    ///the state of the servers as prometheus metrics
+    pub fn collect(&self, out:&mut String) {
+        let upstreams = self.inner.balancer.upstreams();
+        let server = |upstream:&Arc<Upstream>| metrics::label("server", &upstream.addr());
+        let seconds = |ms:Option<u64>| ms.map(|ms| ms as f64 / 1000.0).unwrap_or(0.0);
+        metrics::family(out, "ss_upstream_up", "gauge", "1 if the health check passes",
+            &upstreams.iter().map(|u| (server(u), if u.is_up() { 1 } else { 0 })).collect::<Vec<_>>());
+        metrics::family(out, "ss_upstream_connections_active", "gauge", "tunnels through the server",
+            &upstreams.iter().map(|u| (server(u), u.active())).collect::<Vec<_>>());
+        metrics::family(out, "ss_upstream_connect_seconds", "gauge", "moving average of the connect time, 0 is unknown",
+            &upstreams.iter().map(|u| (server(u), seconds(u.latency()))).collect::<Vec<_>>());
+        metrics::family(out, "ss_upstream_check_seconds", "gauge", "time of the last health check, 0 is unknown",
+            &upstreams.iter().map(|u| (server(u), seconds(u.check_latency()))).collect::<Vec<_>>());
+        metrics::family(out, "ss_upstream_check_fails", "gauge", "consecutive failed health checks",
+            &upstreams.iter().map(|u| (server(u), u.fails())).collect::<Vec<_>>());
+    }
+}
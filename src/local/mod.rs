@@ -1,5 +1,23 @@
 mod protocol;
 
+pub mod balance;
+pub use self::balance::Balancer;
+
+pub mod health;
+pub use self::health::HealthChecker;
+
+pub mod gfwlist;
+pub use self::gfwlist::GfwList;
+
+pub mod rule;
+pub use self::rule::Router;
+
+pub mod pac;
+pub use self::pac::Pac;
+
+mod watch;
+pub use self::watch::Watcher;
+
 mod server;
 pub use self::server::LocalServer;
 
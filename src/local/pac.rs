@@ -0,0 +1,377 @@
+//! proxy auto-config for the browsers, served over http as /proxy.pac.
+//!
+//! the script holds the routing rules and the gfwlist and decides like the
+//! router. the direct connections return DIRECT, the others go to sslocal,
+//! which proxies or rejects them by the same rules.
+
+use define::ErrCode;
+use define::ErrCode::*;
+
+use std::io::{Read, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use serde_json::Value;
+
+use local::gfwlist::GfwList;
+use local::rule::Rule;
+
+const SCRIPT:&str = r#"
+function urlPort(url) {
+    var m = /^([a-z0-9+.-]+):\/\/(?:[^\/@]*@)?(\[[^\]]*\]|[^\/:?#]*)(?::(\d+))?/i.exec(url);
+    if (!m) return 0;
+    if (m[3]) return parseInt(m[3], 10);
+    var scheme = m[1].toLowerCase();
+    if (scheme == "https" || scheme == "wss") return 443;
+    if (scheme == "ftp") return 21;
+    return 80;
+}
+
+function ip4(s) {
+    var parts = s.split(".");
+    if (parts.length != 4) return null;
+    var n = 0;
+    for (var i = 0; i < 4; i++) {
+        if (!/^\d{1,3}$/.test(parts[i])) return null;
+        var x = parseInt(parts[i], 10);
+        if (x > 255) return null;
+        n = n * 256 + x;
+    }
+    return n;
+}
+
+//the 8 groups of an ipv6 literal, an ipv4 tail fills the last two
+function ip6(s) {
+    var pos = s.lastIndexOf(":"), tail = null;
+    if (pos < 0) return null;
+    if (s.indexOf(".") >= 0) {
+        tail = ip4(s.substring(pos + 1));
+        if (tail === null) return null;
+        s = s.substring(0, pos + 1) + "0:0";
+    }
+    var halves = s.split("::");
+    if (halves.length > 2) return null;
+    var groups = halves[0] ? halves[0].split(":") : [];
+    var rest = halves.length == 2 && halves[1] ? halves[1].split(":") : [];
+    var fill = 8 - groups.length - rest.length;
+    if (halves.length == 1 ? fill != 0 : fill < 1) return null;
+    for (var i = 0; i < fill; i++) groups.push("0");
+    groups = groups.concat(rest);
+    var n = [];
+    for (var i = 0; i < 8; i++) {
+        if (!/^[0-9a-f]{1,4}$/.test(groups[i])) return null;
+        n.push(parseInt(groups[i], 16));
+    }
+    if (tail !== null) {
+        n[6] = Math.floor(tail / 65536);
+        n[7] = tail % 65536;
+    }
+    return n;
+}
+
+//a network only matches the literals of its own family, like the router
+function inCidr(host, value) {
+    var pos = value.indexOf("/");
+    var len = parseInt(value.substring(pos + 1), 10);
+    var ip = ip4(host), net = ip4(value.substring(0, pos));
+    if (ip !== null && net !== null) {
+        var d = Math.pow(2, 32 - len);
+        return Math.floor(ip / d) == Math.floor(net / d);
+    }
+    ip = ip6(host);
+    net = ip6(value.substring(0, pos));
+    if (ip === null || net === null) return false;
+    for (var i = 0; len > 0; i++, len -= 16) {
+        var d = Math.pow(2, 16 - Math.min(len, 16));
+        if (Math.floor(ip[i] / d) != Math.floor(net[i] / d)) return false;
+    }
+    return true;
+}
+
+function wildcard(pattern, text) {
+    var parts = pattern.split("*"), pos = 0;
+    for (var i = 0; i < parts.length; i++) {
+        if (!parts[i]) continue;
+        var found = text.indexOf(parts[i], pos);
+        if (found < 0) return false;
+        pos = found + parts[i].length;
+    }
+    return true;
+}
+
+function patternsMatch(p, host, url) {
+    if (p.hosts.hasOwnProperty(host)) return true;
+    var domain = host;
+    while (true) {
+        if (p.domains.hasOwnProperty(domain)) return true;
+        var pos = domain.indexOf(".");
+        if (pos < 0) break;
+        domain = domain.substring(pos + 1);
+    }
+    for (var i = 0; i < p.keywords.length; i++) {
+        if (wildcard(p.keywords[i], url)) return true;
+    }
+    for (var i = 0; i < p.regexes.length; i++) {
+        if (p.regexes[i].test(url)) return true;
+    }
+    return false;
+}
+
+//a pattern the browser rejects is skipped, not the whole pac
+function regex(source) {
+    try {
+        return new RegExp(source);
+    } catch (e) {
+        return null;
+    }
+}
+
+function compile(p) {
+    var regexes = [];
+    for (var i = 0; i < p.regexes.length; i++) {
+        var re = regex(p.regexes[i]);
+        if (re) regexes.push(re);
+    }
+    p.regexes = regexes;
+}
+if (gfwlist) {
+    compile(gfwlist.block);
+    compile(gfwlist.allow);
+}
+for (var i = 0; i < rules.length; i++) {
+    if (rules[i][0] == "DOMAIN-REGEX") rules[i][1] = regex(rules[i][1]);
+}
+
+//1 listed, 2 excepted, 0 unlisted
+function gfwCheck(host, url) {
+    url = url.toLowerCase();
+    if (patternsMatch(gfwlist.allow, host, url)) return 2;
+    if (patternsMatch(gfwlist.block, host, url)) return 1;
+    return 0;
+}
+
+function ruleMatch(rule, host, port) {
+    var value = rule[1];
+    switch (rule[0]) {
+    case "DOMAIN": return host == value;
+    case "DOMAIN-SUFFIX": return host == value || host.slice(-(value.length + 1)) == "." + value;
+    case "DOMAIN-KEYWORD": return host.indexOf(value) >= 0;
+    case "DOMAIN-REGEX": return value != null && value.test(host);
+    case "IP-CIDR": return inCidr(host, value);
+    case "DST-PORT":
+        var range = value.split("-");
+        return port >= parseInt(range[0], 10) && port <= parseInt(range[1], 10);
+    case "MATCH": return true;
+    }
+    return false;
+}
+
+function decide(action) {
+    return action == "direct" ? "DIRECT" : proxy;
+}
+
+function FindProxyForURL(url, host) {
+    host = host.toLowerCase().replace(/^\[|\]$/g, "");
+    var port = urlPort(url);
+    var hasGfwlistRule = false;
+    for (var i = 0; i < rules.length; i++) {
+        if (rules[i][0] == "GFWLIST") {
+            hasGfwlistRule = true;
+            if (!gfwlist) continue;
+            var verdict = gfwCheck(host, url);
+            if (verdict == 1) return decide(rules[i][2]);
+            if (verdict == 2) return "DIRECT";
+            continue;
+        }
+        if (ruleMatch(rules[i], host, port)) return decide(rules[i][2]);
+    }
+    if (gfwlist && !hasGfwlistRule) {
+        return gfwCheck(host, url) == 1 ? proxy : "DIRECT";
+    }
+    return proxy;
+}
+"#;
+
+///the script without the proxy, which depends on the address the browser connected
+pub fn generate(rules:&[Rule], gfwlist:Option<&GfwList>) -> String {
+    let rules:Vec<Value> = rules.iter().map(|rule| {
+        let (kind, value) = rule.describe();
+        Value::from(vec![kind.to_string(), value, rule.action().as_str().to_string()])
+    }).collect();
+    let gfwlist = gfwlist.map(|list| list.to_json()).unwrap_or(Value::Null);
+    format!("var rules = {};\nvar gfwlist = {};\n{}", Value::from(rules), gfwlist, SCRIPT)
+}
+
+#[derive(Debug)]
+struct Inner {
+    script: RwLock<Arc<String>>,
+    proxy: RwLock<(String, u32)>,
+    listening: Mutex<Option<String>>, //the address of the current listener
+}
+
+///the current pac file, replaced on reload
+#[derive(Debug, Clone)]
+pub struct Pac {
+    inner: Arc<Inner>,
+}
+
+impl Pac {
+
+    pub fn new() -> Self {
+        let inner = Inner {
+            script: RwLock::new(Arc::new(generate(&[], None))),
+            proxy: RwLock::new(("127.0.0.1".to_string(), 1080)),
+            listening: Mutex::new(None),
+        };
+        Pac {
+            inner: Arc::new(inner),
+        }
+    }
+
+    ///the proxy is local_address:local_port of sslocal
+    pub fn set(&self, script:String, ip:&str, port:u32) -> Result<(), ErrCode> {
+        *self.inner.script.write().or(Err(LockErr))? = Arc::new(script);
+        *self.inner.proxy.write().or(Err(LockErr))? = (ip.to_string(), port);
+        Ok(())
+    }
+
+    ///an unspecified listen address is replaced by the address the browser connected
+    fn render(&self, local_ip:Option<IpAddr>) -> Result<String, ErrCode> {
+        let script = self.inner.script.read().or(Err(LockErr))?.clone();
+        let (mut ip, port) = self.inner.proxy.read().or(Err(LockErr))?.clone();
+        if ip.is_empty() || ip == "0.0.0.0" || ip == "::" {
+            if let Some(local_ip) = local_ip {
+                ip = local_ip.to_string();
+            }
+        }
+        if ip.contains(':') {
+            ip = format!("[{}]", ip);
+        }
+        Ok(format!("var proxy = \"SOCKS5 {0}:{1}; SOCKS {0}:{1}\";\n{2}", ip, port, script))
+    }
+
+    ///serve the pac file in the background, the listener of another address is stopped
+    ///after the bind, which keeps it if the bind fails
+    pub fn serve(&self, ip:&str, port:u32) -> Result<(), ErrCode> {
+        let url = format!("{}:{}", ip, port);
+        let old = {
+            let mut listening = self.inner.listening.lock().or(Err(LockErr))?;
+            if listening.as_ref() == Some(&url) {
+                return Ok(());
+            }
+            let listener = TcpListener::bind(&url).or_else(|e|{
+                error!("{}", e);
+                Err(UrlErr)
+            })?;
+            info!("pac start listening on {}, http://{}/proxy.pac", url, url);
+            let pac = self.clone();
+            let current = url.clone();
+            let _ = thread::spawn(move || pac.accept(listener, current));
+            listening.replace(url)
+        };
+        if let Some(old) = old {
+            wake(&old);
+        }
+        Ok(())
+    }
+
+    ///stop serving, when pac_port is removed
+    pub fn stop(&self) -> Result<(), ErrCode> {
+        let old = self.inner.listening.lock().or(Err(LockErr))?.take();
+        if let Some(old) = old {
+            wake(&old);
+        }
+        Ok(())
+    }
+
+    fn accept(&self, listener:TcpListener, url:String) {
+        for stream_rst in listener.incoming() {
+            let current = self.inner.listening.lock().map(|listening| listening.as_ref() == Some(&url)).unwrap_or(false);
+            if !current {
+                info!("pac stop listening on {}", url);
+                break;
+            }
+            if let Ok(stream) = stream_rst {
+                let pac = self.clone();
+                let _ = thread::spawn(move || {
+                    let _ = pac.handle_stream(stream);
+                });
+            }
+        }
+    }
+
+    fn handle_stream(&self, mut stream:TcpStream) -> Result<(), ErrCode> {
+        let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+        let _ = stream.set_write_timeout(Some(Duration::from_secs(5)));
+        let mut head = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+            let size = stream.read(&mut buf).or(Err(SocketErr))?;
+            if size == 0 || head.len() > 8192 {
+                return Err(SocketErr);
+            }
+            head.extend_from_slice(&buf[..size]);
+        }
+        let head = String::from_utf8_lossy(&head);
+        let mut parts = head.lines().next().unwrap_or("").split_whitespace();
+        let method = parts.next().unwrap_or("");
+        let path = parts.next().unwrap_or("").split('?').next().unwrap_or("");
+
+        let (status, body) = if method != "GET" && method != "HEAD" {
+            ("405 Method Not Allowed", String::new())
+        } else if path != "/" && path != "/proxy.pac" {
+            ("404 Not Found", String::new())
+        } else {
+            let local_ip = stream.local_addr().ok().map(|addr| addr.ip());
+            ("200 OK", self.render(local_ip)?)
+        };
+        let mut response = format!("HTTP/1.1 {}\r\nContent-Type: application/x-ns-proxy-autoconfig\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status, body.len());
+        if method != "HEAD" {
+            response.push_str(&body);
+        }
+        let _ = stream.write_all(response.as_bytes()).or(Err(SocketErr))?;
+        Ok(())
+    }
+}
+
+///the accept of a replaced listener returns and sees it is no longer current
+fn wake(url:&str) {
+    let _ = TcpStream::connect(url);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn free_port() -> u32 {
+        TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port() as u32
+    }
+
+    fn fetch(port:u32) -> Option<String> {
+        let mut stream = TcpStream::connect(format!("127.0.0.1:{}", port)).ok()?;
+        let _ = stream.write_all(b"GET /proxy.pac HTTP/1.1\r\n\r\n").ok()?;
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response).ok()?;
+        response.lines().next().map(|line| line.to_string())
+    }
+
+    #[test]
+    fn rebind_and_stop() {
+        let pac = Pac::new();
+        let (first, second) = (free_port(), free_port());
+        pac.serve("127.0.0.1", first).unwrap();
+        pac.serve("127.0.0.1", first).unwrap();
+        assert_eq!(fetch(first), Some("HTTP/1.1 200 OK".to_string()));
+        pac.serve("127.0.0.1", second).unwrap();
+        assert_eq!(fetch(second), Some("HTTP/1.1 200 OK".to_string()));
+        //the wake connection has been accepted, the old listener is closed
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(fetch(first), None);
+        pac.stop().unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(fetch(second), None);
+    }
+}
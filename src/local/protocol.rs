@@ -1,11 +1,11 @@
-use define::{Ip, ErrCode};
+use define::{Ip, ErrCode, Reply};
 use define::ErrCode::*;
 
-use std::net::{Shutdown, TcpStream, Ipv4Addr};
+use std::net::{Shutdown, TcpStream, Ipv4Addr, Ipv6Addr, SocketAddr, IpAddr};
 use std::net::ToSocketAddrs;
 use std::io::{Read, Write};
 use std::io::Cursor;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::collections::HashSet;
 use std::{thread};
 
@@ -19,6 +19,12 @@ use std::sync::mpsc::{channel};
 
 use helper;
 use helper::encode;
+use local::balance::{Balancer, Upstream, Lease};
+use local::rule::{Router, Target, Action};
+use metrics::Connection;
+use access;
+use access::Session;
+use limit::Throttle;
 
 #[derive(Default, Debug)]
 struct ConnectHead {
@@ -27,6 +33,7 @@ struct ConnectHead {
     rsv: u8,
     atyp: u8,
     ip: Ip,
+    ipv6: Option<Ipv6Addr>,
     url: String,
     port: u16,
 }
@@ -60,6 +67,27 @@ impl ConnectHead {
     pub fn set_url(&mut self, url:&str) {
         self.url = url.to_string();
     }
+
+    pub fn set_ipv6(&mut self, ipv6:Ipv6Addr) {
+        self.ipv6 = Some(ipv6);
+    }
+
+    ///the requested ip, none for a domain
+    pub fn ip_addr(&self) -> Option<IpAddr> {
+        match self.atyp {
+            1 => Some(IpAddr::V4(self.ip.to_ipv4())),
+            4 => self.ipv6.map(IpAddr::V6),
+            _ => None,
+        }
+    }
+
+    ///the requested destination, host:port
+    pub fn target(&self) -> String {
+        match self.ip_addr() {
+            Some(ip) => format!("{}", SocketAddr::new(ip, self.port)),
+            None => format!("{}:{}", self.url, self.port),
+        }
+    }
 }
 
 #[derive(Default, Debug)]
@@ -104,15 +132,21 @@ pub struct Protocol {
     start_head: StartHead,
     conn_head: ConnectHead,
     target_stream: Option<TcpStream>, //stream to the target
-    remote_ip: String,
-    remote_port: u32,
+    balancer: Balancer,
+    lease: Option<Lease>, //the server in use
+    router: Router,
+    direct: bool, //the target is connected without the server
     time_out: u64,
+    connect_status: bool, //wait for the status frame of the server
+    conn: Connection,
+    session: Session,
+    throttle: Throttle,
 }
 
 impl Protocol {
     
-    pub fn new(stream:TcpStream, remote_ip:String, remote_port:u32, time_out:u64) -> Self {
-        let _ = stream.set_read_timeout(Some(Duration::from_millis(time_out)));
+    pub fn new(stream:TcpStream, balancer:Balancer, router:Router, time_out:u64, connect_status:bool, conn:Connection, session:Session) -> Self {
+        let _ = stream.set_read_timeout(Some(Duration::from_secs(time_out)));
         Protocol {
             stream: stream,
             buf: BytesMut::with_capacity(1024),
@@ -121,12 +155,31 @@ impl Protocol {
             conn_head: Default::default(),
             target_stream: None,
             time_out: time_out,
-            remote_ip: remote_ip,
-            remote_port: remote_port,
+            balancer: balancer,
+            lease: None,
+            router: router,
+            direct: false,
+            connect_status: connect_status,
+            conn: conn,
+            session: session,
+            throttle: Default::default(),
         }
     }
 
+    ///the bandwidth limits of the tunnel
+    pub fn set_throttle(&mut self, throttle:Throttle) {
+        self.throttle = throttle;
+    }
+
+    ///serve the stream, then write the access log
     pub fn start(&mut self) -> Result<(), ErrCode> {
+        let rst = self.serve();
+        let (up, down) = self.conn.bytes();
+        self.session.finish(up, down);
+        rst
+    }
+
+    fn serve(&mut self) -> Result<(), ErrCode> {
         let mut buf = vec![0u8; 1024];
         loop {
             let rst = self.stream.read(&mut buf);
@@ -146,24 +199,60 @@ impl Protocol {
                 }
             }
         }
+        //closed or timed out before the heads were complete
+        if self.step != ProStep::ConnectTarget {
+            self.handshake_failure(SocketErr);
+        }
         Ok(())
     }
 
+    fn handshake_failure(&mut self, e:ErrCode) {
+        self.conn.handshake_failure(e);
+        self.session.end("handshake_failed", Some(format!("{:?}", e)));
+    }
+
     pub fn handle(&mut self) -> Result<(), ErrCode> {
         match self.step {
             ProStep::Start => {
-                let _ = self.get_start_head()?;
+                if let Err(e) = self.get_start_head() {
+                    self.handshake_failure(e);
+                    return Err(e);
+                }
             },
             ProStep::Connect => {
-                let _ = self.connect()?;
-                let rst = self.connect_target();
+                if let Err(e) = self.connect() {
+                    self.handshake_failure(e);
+                    return Err(e);
+                }
+                //the connect head is not finished
+                if self.step != ProStep::ConnectTarget {
+                    return Ok(());
+                }
+                let action = {
+                    let head = &self.conn_head;
+                    let host = if head.atyp == 3 { Some(head.url.as_str()) } else { None };
+                    self.session.set_target(host, head.ip_addr(), head.port);
+                    self.router.route(&Target::new(host, head.ip_addr(), head.port))
+                };
+                self.session.set_decision(action.as_str());
+                info!("{} {}", action.as_str(), self.conn_head.target());
+                let start = Instant::now();
+                let rst = match action {
+                    Action::Proxy => self.connect_target(),
+                    Action::Direct => self.connect_direct(),
+                    Action::Reject => Err(Reply::NotAllowed),
+                };
                 match rst {
                     Ok(_) => {
+                        self.conn.connected(start.elapsed());
                         let _ = self.connect_success()?;
                         let _ = self.tunnel()?;
                     },
-                    Err(_e) => {
-                        let _ = self.connect_err()?;
+                    Err(rep) => {
+                        error!("connect {} failed, {}.", self.conn_head.target(), rep.description());
+                        self.conn.connect_failure(rep);
+                        self.session.end("connect_failed", Some(format!("{:?}", rep)));
+                        let _ = self.connect_err(rep)?;
                     },
                 }
             },
@@ -210,6 +299,16 @@ impl Protocol {
                     let ip = Ip::new(fs, s, t, f);
                     head.set_ip(ip);
                 },
+                4 => {
+                    if self.buf.len() < 22 {
+                        return Ok(());
+                    }
+                    let _ = self.buf.split_to(4);
+                    let ip_buf = self.buf.split_to(16);
+                    let mut octets = [0u8; 16];
+                    octets.copy_from_slice(&ip_buf);
+                    head.set_ipv6(Ipv6Addr::from(octets));
+                },
                 3 => {
                     if self.buf.len() < 5 {
                         return Ok(());
@@ -224,6 +323,7 @@ impl Protocol {
                     head.set_url(&url);
                 },
                 _ => {
+                    let _ = self.connect_err(Reply::AddrTypeNotSupported)?;
                     return Err(UnImplementErr);
                 }
             }
@@ -233,12 +333,17 @@ impl Protocol {
         let port = cur.read_u16::<BigEndian>().or(Err(SocketErr))?;
         head.set_port(port);
         info!("{:?} and buf len is {}.", head, self.buf.len());
+        //only CONNECT is supported
+        if cmd != 1 {
+            let _ = self.connect_err(Reply::CommandNotSupported)?;
+            return Err(UnImplementErr);
+        }
         self.conn_head = head;
         self.step.next();
         Ok(())
     }
 
-    pub fn connect_target(&mut self) -> Result<(), ErrCode> {
+    pub fn connect_target(&mut self) -> Result<(), Reply> {
         /*
         let atyp = self.conn_head.atyp;
         let ipv4_addr;
@@ -255,16 +360,76 @@ impl Protocol {
         self.target_stream = Some(TcpStream::connect((ipv4_addr, self.conn_head.port)).or(Err(NetErr))?);
         */
         let time_out = Duration::from_secs(self.time_out);
-        let uri = format!("{}:{}", self.remote_ip, self.remote_port);
-        let addr = uri.parse().or(Err(NetErr))?;
-
-        let target_stream = TcpStream::connect_timeout(&addr, time_out).or(Err(NetErr))?;
-        self.target_stream = Some(target_stream);
+        //fail over to the next server if one can't be reached
+        let mut rep = Reply::GeneralFailure;
+        for upstream in self.balancer.candidates() {
+            match Upstream::connect(&upstream, time_out) {
+                Ok((target_stream, lease)) => {
+                    self.session.set_upstream(&upstream.addr());
+                    self.target_stream = Some(target_stream);
+                    self.lease = Some(lease);
+                    break;
+                },
+                Err(e) => {
+                    error!("connect server {} failed, {}", upstream.addr(), e);
+                    rep = Reply::from(&e);
+                },
+            }
+        }
+        if self.target_stream.is_none() {
+            return Err(rep);
+        }
 
-        let _ = self.write_ss_head()?;
+        let _ = self.write_ss_head().or(Err(Reply::GeneralFailure))?;
+        if self.connect_status {
+            let rep = self.read_status()?;
+            if rep != Reply::Succeeded {
+                return Err(rep);
+            }
+        }
         Ok(())
     }
 
+    ///connect the target without the server, the tunnel is not encoded
+    pub fn connect_direct(&mut self) -> Result<(), Reply> {
+        let time_out = Duration::from_secs(self.time_out);
+        let addrs:Vec<SocketAddr> = match self.conn_head.ip_addr() {
+            Some(ip) => vec![SocketAddr::new(ip, self.conn_head.port)],
+            None => (self.conn_head.url.as_str(), self.conn_head.port).to_socket_addrs()
+                .or(Err(Reply::HostUnreachable))?.collect(),
+        };
+        let mut rep = Reply::HostUnreachable;
+        for addr in addrs {
+            match TcpStream::connect_timeout(&addr, time_out) {
+                Ok(mut target_stream) => {
+                    //write the upload buf
+                    let _ = target_stream.write_all(&self.buf).or(Err(Reply::GeneralFailure))?;
+                    self.session.set_ip(addr.ip());
+                    self.target_stream = Some(target_stream);
+                    self.direct = true;
+                    return Ok(());
+                },
+                Err(e) => {
+                    error!("connect {} directly failed, {}", addr, e);
+                    rep = Reply::from(&e);
+                },
+            }
+        }
+        Err(rep)
+    }
+
+    ///read the status frame the server sends after dialing the target
+    pub fn read_status(&mut self) -> Result<Reply, Reply> {
+        let mut stream = self.target_stream.as_ref().ok_or(Reply::GeneralFailure)?;
+        let _ = stream.set_read_timeout(Some(Duration::from_secs(self.time_out)));
+        let mut status = [0u8; 1];
+        let _ = stream.read_exact(&mut status).map_err(|e| {
+            error!("read the status of the server failed, {}", e);
+            Reply::GeneralFailure
+        })?;
+        Ok(Reply::from_u8(encode(&status)[0]))
+    }
+
     ///send the ss head
     pub fn write_ss_head(&mut self) -> Result<(), ErrCode> {
         let mut buf = BytesMut::new();
@@ -278,6 +443,10 @@ impl Protocol {
             buf.put_u8(ip.second);
             buf.put_u8(ip.third);
             buf.put_u8(ip.forth);
+        } else if atyp == 4 {
+            let ipv6 = self.conn_head.ipv6.ok_or(SocketErr)?;
+            buf.reserve(16);
+            buf.put_slice(&ipv6.octets());
         } else {
             let url_bytes = self.conn_head.url.as_bytes();
             buf.reserve(url_bytes.len() + 1);
@@ -294,9 +463,12 @@ impl Protocol {
     }
 
     pub fn tunnel(&mut self) -> Result<(), ErrCode> {
-        let (sx, rx) = channel::<u64>();
+        let (sx, rx) = channel::<&'static str>();
         let stream = self.stream.try_clone().or(Err(SocketErr))?;
         let target_stream = self.target_stream.take().ok_or(SocketErr)?;
+        let direct = self.direct;
+        //the upload buf is written while connecting
+        self.conn.meter().up(self.buf.len());
 
         //write time out 1 minute
         let _ = stream.set_write_timeout(Some(Duration::from_millis(60*1000))).or(Err(SocketErr))?;
@@ -310,112 +482,115 @@ impl Protocol {
         let _ = target_stream.set_read_timeout(None).or(Err(SocketErr))?;
 
         let sx1 = sx.clone();
+        let meter1 = self.conn.meter();
+        let throttle1 = self.throttle.clone();
         let mut stream_read = stream.try_clone().or(Err(SocketErr))?;
         let mut target_stream_write = target_stream.try_clone().or(Err(SocketErr))?;
         let _th1 = thread::spawn(move || {
             let mut buf = vec![0u8; 1024];
-            loop {
+            let reason = loop {
                 let rst = stream_read.read(&mut buf);
                 match rst {
                     Ok(size) => {
                         //info!("local stream receive {} bytes data.", size);
                         if size == 0 {
-                            break;
+                            break "client_closed";
                         }
-                        let rst = target_stream_write.write_all(&encode(&buf[0..size]));
-                        if rst.is_err() {
-                            break;
+                        throttle1.up(size);
+                        let rst = if direct {
+                            target_stream_write.write_all(&buf[0..size])
+                        } else {
+                            target_stream_write.write_all(&encode(&buf[0..size]))
+                        };
+                        if let Err(e) = rst {
+                            break access::io_reason(&e, "target_error");
                         }
+                        meter1.up(size);
                     },
                     Err(e) => {
                         error!("{}", e);
-                        break;
+                        break access::io_reason(&e, "client_error");
                     }
                 }
-            }
-            let _ = sx1.send(0);
+            };
+            let _ = sx1.send(reason);
         });
 
         let sx2 = sx.clone();
+        let meter2 = self.conn.meter();
+        let throttle2 = self.throttle.clone();
         let mut stream_write = stream.try_clone().or(Err(SocketErr))?;
         let mut target_stream_read = target_stream.try_clone().or(Err(SocketErr))?;
         let _th2 = thread::spawn(move || {
             let mut buf = vec![0u8; 1024];
-            loop {
+            let reason = loop {
                 let rst = target_stream_read.read(&mut buf);
                 match rst {
                     Ok(size) => {
                         //info!("target stream receive {} bytes data.", size);
                         if size == 0 {
-                            break;
+                            break "target_closed";
                         }
-                        let rst = stream_write.write_all(&encode(&buf[0..size]));
-                        if rst.is_err() {
-                            break;
+                        throttle2.down(size);
+                        let rst = if direct {
+                            stream_write.write_all(&buf[0..size])
+                        } else {
+                            stream_write.write_all(&encode(&buf[0..size]))
+                        };
+                        if let Err(e) = rst {
+                            break access::io_reason(&e, "client_error");
                         }
+                        meter2.down(size);
                     },
                     Err(e) => {
                         error!("{}", e);
-                        break;
+                        break access::io_reason(&e, "target_error");
                     }
                 }
-            }
-            let _ = sx2.send(0);
+            };
+            let _ = sx2.send(reason);
         });
 
         //th1 or th2 finished, will return.
-        let _ = rx.recv().or(Err(SocketErr))?;
+        let reason = rx.recv().or(Err(SocketErr))?;
+        self.session.end(reason, None);
         let _ = stream.shutdown(Shutdown::Both);
         let _ = target_stream.shutdown(Shutdown::Both);
 
         Err(SocketErr)
     }
 
-    ///connect the target success
+    ///connect the target success, BND.ADDR is the address bound to the server connection
     pub fn connect_success(&mut self) -> Result<(), ErrCode> {
-        let mut buf = BytesMut::from(vec![5, 0, 0]);
-        let atyp = self.conn_head.atyp;
-        buf.reserve(1);
-        buf.put_u8(atyp);
-        if atyp == 1 {
-            buf.reserve(4);
-            let ip = &self.conn_head.ip;
-            buf.put_u8(ip.first);
-            buf.put_u8(ip.second);
-            buf.put_u8(ip.third);
-            buf.put_u8(ip.forth);
-        } else {
-            let url_bytes = self.conn_head.url.as_bytes();
-            buf.reserve(url_bytes.len() + 1);
-            buf.put_u8(url_bytes.len() as u8);
-            buf.put_slice(&url_bytes);
-        }
-        buf.reserve(2);
-        buf.put_u16::<BigEndian>(self.conn_head.port);
-        let _ = self.stream.write_all(&buf).or(Err(SocketErr))?;
-        Ok(())
+        let bind_addr = {
+            let target_stream = self.target_stream.as_ref().ok_or(SocketErr)?;
+            target_stream.local_addr().or(Err(SocketErr))?
+        };
+        self.reply(Reply::Succeeded, bind_addr)
     }
     
-    pub fn connect_err(&mut self) -> Result<(), ErrCode> {
-        let mut buf = BytesMut::from(vec![5, 1, 0]);
-        let atyp = self.conn_head.atyp;
-        buf.reserve(1);
-        buf.put_u8(atyp);
-        if atyp == 1 {
-            buf.reserve(4);
-            let ip = &self.conn_head.ip;
-            buf.put_u8(ip.first);
-            buf.put_u8(ip.second);
-            buf.put_u8(ip.third);
-            buf.put_u8(ip.forth);
-        } else {
-            let url_bytes = self.conn_head.url.as_bytes();
-            buf.reserve(url_bytes.len() + 1);
-            buf.put_u8(url_bytes.len() as u8);
-            buf.put_slice(&url_bytes);
+    pub fn connect_err(&mut self, rep:Reply) -> Result<(), ErrCode> {
+        let bind_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0);
+        self.reply(rep, bind_addr)
+    }
+
+    ///write the socks5 reply: VER REP RSV ATYP BND.ADDR BND.PORT
+    pub fn reply(&mut self, rep:Reply, bind_addr:SocketAddr) -> Result<(), ErrCode> {
+        let mut buf = BytesMut::from(vec![5, u8::from(rep), 0]);
+        match bind_addr.ip() {
+            IpAddr::V4(ip) => {
+                buf.reserve(1 + 4);
+                buf.put_u8(1);
+                buf.put_slice(&ip.octets());
+            },
+            IpAddr::V6(ip) => {
+                buf.reserve(1 + 16);
+                buf.put_u8(4);
+                buf.put_slice(&ip.octets());
+            },
         }
         buf.reserve(2);
-        buf.put_u16::<BigEndian>(self.conn_head.port);
+        buf.put_u16::<BigEndian>(bind_addr.port());
         let _ = self.stream.write_all(&buf).or(Err(SocketErr))?;
         Ok(())
     }
@@ -0,0 +1,358 @@
+//! routing rules of sslocal, one rule per line: TYPE,VALUE,ACTION
+//!
+//! ```text
+//! DOMAIN,example.com,proxy
+//! DOMAIN-SUFFIX,cn,direct
+//! DOMAIN-KEYWORD,ads,reject
+//! DOMAIN-REGEX,^img[0-9]+\.example\.org$,proxy
+//! IP-CIDR,192.168.0.0/16,direct
+//! IP-CIDR,fd00::/8,direct
+//! DST-PORT,25,reject
+//! DST-PORT,8000-9000,direct
+//! GFWLIST,proxy
+//! MATCH,proxy
+//! ```
+//!
+//! the first matching rule wins, without a match the connection is proxied.
+//! GFWLIST matches the hosts listed in the gfwlist, its @@ exceptions connect
+//! directly. with a gfwlist but no GFWLIST rule the listed hosts are proxied
+//! after the rules and the others connect directly.
+
+use define::ErrCode;
+use define::ErrCode::*;
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+
+extern crate regex;
+use self::regex::Regex;
+
+use helper::{parse_cidr, cidr_contains, parse_port_range};
+use local::gfwlist::{GfwList, Verdict};
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum Action {
+    Proxy, //through the server
+    Direct, //connect the target without the server
+    Reject, //answer the client with connection not allowed
+}
+
+impl Action {
+
+    pub fn from_name(action:&str) -> Option<Action> {
+        match action.to_lowercase().as_str() {
+            "proxy" => Some(Action::Proxy),
+            "direct" => Some(Action::Direct),
+            "reject" => Some(Action::Reject),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Action::Proxy => "proxy",
+            Action::Direct => "direct",
+            Action::Reject => "reject",
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Matcher {
+    Domain(String),
+    DomainSuffix(String),
+    DomainKeyword(String),
+    DomainRegex(Regex),
+    IpCidr(IpAddr, u8),
+    DstPort(u16, u16),
+    GfwList,
+    Match,
+}
+
+#[derive(Debug)]
+pub struct Rule {
+    matcher: Matcher,
+    action: Action,
+}
+
+///the destination of a connection, host is none for an ip request
+#[derive(Debug, Clone, Copy)]
+pub struct Target<'a> {
+    pub host: Option<&'a str>,
+    pub ip: Option<IpAddr>,
+    pub port: u16,
+}
+
+impl<'a> Target<'a> {
+
+    ///a domain which is an ip literal is matched as the ip
+    pub fn new(host:Option<&'a str>, ip:Option<IpAddr>, port:u16) -> Self {
+        let ip = ip.or_else(|| host.and_then(|h| h.trim_matches(|c| c == '[' || c == ']').parse().ok()));
+        Target {
+            host: host,
+            ip: ip,
+            port: port,
+        }
+    }
+}
+
+impl Rule {
+
+    pub fn parse(line:&str) -> Result<Rule, String> {
+        //the type is before the first comma and the action after the last one,
+        //so a regex may contain commas
+        let mut parts = line.splitn(2, ',');
+        let name = parts.next().unwrap_or("").trim();
+        let kind = name.to_uppercase();
+        let rest = parts.next();
+        let (value, action) = match (kind.as_str(), rest) {
+            ("MATCH", Some(action)) | ("GFWLIST", Some(action)) if !action.contains(',') => ("", action.trim()),
+            ("MATCH", _) | ("GFWLIST", _) => return Err(format!("{} needs an action", kind)),
+            (_, Some(rest)) if rest.contains(',') => {
+                let mut parts = rest.rsplitn(2, ',');
+                let action = parts.next().unwrap_or("").trim();
+                (parts.next().unwrap_or("").trim(), action)
+            },
+            _ => return Err("needs TYPE,VALUE,ACTION".to_string()),
+        };
+        let action = Action::from_name(action).ok_or(format!("unknown action {}", action))?;
+        let matcher = match kind.as_str() {
+            "DOMAIN" => Matcher::Domain(value.to_lowercase()),
+            "DOMAIN-SUFFIX" => Matcher::DomainSuffix(value.trim_start_matches('.').to_lowercase()),
+            "DOMAIN-KEYWORD" => Matcher::DomainKeyword(value.to_lowercase()),
+            "DOMAIN-REGEX" => Matcher::DomainRegex(Regex::new(value).map_err(|e| format!("invalid regex, {}", e))?),
+            "IP-CIDR" | "IP-CIDR6" => {
+                let (ip, len) = parse_cidr(value).ok_or(format!("invalid cidr {}", value))?;
+                Matcher::IpCidr(ip, len)
+            },
+            "DST-PORT" => {
+                let (low, high) = parse_port_range(value).ok_or(format!("invalid port {}", value))?;
+                Matcher::DstPort(low, high)
+            },
+            "GFWLIST" => Matcher::GfwList,
+            "MATCH" => Matcher::Match,
+            _ => return Err(format!("unknown rule type {}", name)),
+        };
+        Ok(Rule {
+            matcher: matcher,
+            action: action,
+        })
+    }
+
+    pub fn action(&self) -> Action {
+        self.action
+    }
+
+    ///the type and the value as written in the rules file
+    pub fn describe(&self) -> (&'static str, String) {
+        match self.matcher {
+            Matcher::Domain(ref domain) => ("DOMAIN", domain.clone()),
+            Matcher::DomainSuffix(ref suffix) => ("DOMAIN-SUFFIX", suffix.clone()),
+            Matcher::DomainKeyword(ref keyword) => ("DOMAIN-KEYWORD", keyword.clone()),
+            Matcher::DomainRegex(ref re) => ("DOMAIN-REGEX", re.as_str().to_string()),
+            Matcher::IpCidr(ref net, len) => ("IP-CIDR", format!("{}/{}", net, len)),
+            Matcher::DstPort(low, high) => ("DST-PORT", format!("{}-{}", low, high)),
+            Matcher::GfwList => ("GFWLIST", String::new()),
+            Matcher::Match => ("MATCH", String::new()),
+        }
+    }
+
+    pub fn is_match(&self, target:&Target) -> bool {
+        let host = target.host.map(|h| h.to_lowercase());
+        match self.matcher {
+            Matcher::Domain(ref domain) => host.map_or(false, |h| &h == domain),
+            Matcher::DomainSuffix(ref suffix) => host.map_or(false, |h| {
+                h == *suffix || h.ends_with(&format!(".{}", suffix))
+            }),
+            Matcher::DomainKeyword(ref keyword) => host.map_or(false, |h| h.contains(keyword.as_str())),
+            Matcher::DomainRegex(ref re) => host.map_or(false, |h| re.is_match(&h)),
+            Matcher::IpCidr(ref net, len) => target.ip.map_or(false, |ip| cidr_contains(net, len, &ip)),
+            Matcher::DstPort(low, high) => target.port >= low && target.port <= high,
+            Matcher::GfwList => false, //checked by the router which holds the list
+            Matcher::Match => true,
+        }
+    }
+}
+
+///the rules file, every invalid line is reported with its number
+pub fn load(path:&str) -> Result<Vec<Rule>, Vec<String>> {
+    let file = File::open(path).map_err(|e| vec![format!("{}: can not open, {}", path, e)])?;
+    let mut rules = Vec::new();
+    let mut errors = Vec::new();
+    for (i, line_rst) in BufReader::new(file).lines().enumerate() {
+        let line = match line_rst {
+            Ok(line) => line,
+            Err(e) => {
+                errors.push(format!("{}:{}: {}", path, i + 1, e));
+                break;
+            },
+        };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+            continue;
+        }
+        match Rule::parse(line) {
+            Ok(rule) => rules.push(rule),
+            Err(msg) => errors.push(format!("{}:{}: {}", path, i + 1, msg)),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(rules)
+}
+
+///the rules shared by the connections, replaced on reload
+#[derive(Debug, Clone)]
+pub struct Router {
+    rules: Arc<RwLock<Arc<Vec<Rule>>>>,
+    gfwlist: Arc<RwLock<Option<Arc<GfwList>>>>,
+}
+
+impl Router {
+
+    pub fn new() -> Self {
+        Router {
+            rules: Arc::new(RwLock::new(Arc::new(Vec::new()))),
+            gfwlist: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    pub fn set_rules(&self, rules:Vec<Rule>) -> Result<(), ErrCode> {
+        let mut current = self.rules.write().or(Err(LockErr))?;
+        *current = Arc::new(rules);
+        Ok(())
+    }
+
+    pub fn set_gfwlist(&self, gfwlist:Option<GfwList>) -> Result<(), ErrCode> {
+        let mut current = self.gfwlist.write().or(Err(LockErr))?;
+        *current = gfwlist.map(Arc::new);
+        Ok(())
+    }
+
+    pub fn route(&self, target:&Target) -> Action {
+        let rules = match self.rules.read() {
+            Ok(rules) => rules.clone(),
+            Err(_) => return Action::Proxy,
+        };
+        let gfwlist = self.gfwlist.read().map(|g| g.clone()).unwrap_or(None);
+        let verdict = || match gfwlist {
+            Some(ref list) => {
+                let host = target.host.map(|h| h.to_string())
+                    .or_else(|| target.ip.map(|ip| ip.to_string()))
+                    .unwrap_or_default();
+                list.check(&host, target.port)
+            },
+            None => Verdict::Unlisted,
+        };
+        let mut has_gfwlist_rule = false;
+        for rule in rules.iter() {
+            if let Matcher::GfwList = rule.matcher {
+                has_gfwlist_rule = true;
+                match verdict() {
+                    Verdict::Listed => return rule.action,
+                    Verdict::Excepted => return Action::Direct,
+                    Verdict::Unlisted => continue,
+                }
+            }
+            if rule.is_match(target) {
+                return rule.action;
+            }
+        }
+        if gfwlist.is_some() && !has_gfwlist_rule {
+            return match verdict() {
+                Verdict::Listed => Action::Proxy,
+                _ => Action::Direct,
+            };
+        }
+        Action::Proxy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host<'a>(host:&'a str, port:u16) -> Target<'a> {
+        Target::new(Some(host), None, port)
+    }
+
+    #[test]
+    fn parse_every_type() {
+        let cases = [
+            ("DOMAIN,Example.com,proxy", "DOMAIN", "example.com", Action::Proxy),
+            ("domain-suffix, .cn ,DIRECT", "DOMAIN-SUFFIX", "cn", Action::Direct),
+            ("DOMAIN-KEYWORD,ads,reject", "DOMAIN-KEYWORD", "ads", Action::Reject),
+            ("DOMAIN-REGEX,^img[0-9]+\\.example\\.org$,proxy", "DOMAIN-REGEX", "^img[0-9]+\\.example\\.org$", Action::Proxy),
+            ("IP-CIDR,192.168.0.0/16,direct", "IP-CIDR", "192.168.0.0/16", Action::Direct),
+            ("IP-CIDR6,fd00::/8,direct", "IP-CIDR", "fd00::/8", Action::Direct),
+            ("DST-PORT,25,reject", "DST-PORT", "25-25", Action::Reject),
+            ("DST-PORT,8000-9000,direct", "DST-PORT", "8000-9000", Action::Direct),
+            ("GFWLIST,proxy", "GFWLIST", "", Action::Proxy),
+            ("MATCH,direct", "MATCH", "", Action::Direct),
+        ];
+        for &(line, kind, value, action) in cases.iter() {
+            let rule = Rule::parse(line).unwrap();
+            assert_eq!(rule.describe(), (kind, value.to_string()), "{}", line);
+            assert_eq!(rule.action(), action, "{}", line);
+        }
+    }
+
+    #[test]
+    fn regex_with_commas() {
+        let rule = Rule::parse("DOMAIN-REGEX,^a{1,3}\\.com$,direct").unwrap();
+        assert_eq!(rule.describe(), ("DOMAIN-REGEX", "^a{1,3}\\.com$".to_string()));
+        assert_eq!(rule.action(), Action::Direct);
+        assert!(rule.is_match(&host("aaa.com", 80)));
+        assert!(!rule.is_match(&host("aaaa.com", 80)));
+    }
+
+    #[test]
+    fn invalid_lines() {
+        let errors = [
+            ("DOMAIN,example.com", "needs TYPE,VALUE,ACTION"),
+            ("DOMAIN", "needs TYPE,VALUE,ACTION"),
+            ("MATCH", "MATCH needs an action"),
+            ("MATCH,proxy,direct", "MATCH needs an action"),
+            ("GFWLIST", "GFWLIST needs an action"),
+            ("DOMAIN,example.com,drop", "unknown action drop"),
+            ("HOST,example.com,proxy", "unknown rule type HOST"),
+            ("IP-CIDR,10.0.0.0/33,direct", "invalid cidr 10.0.0.0/33"),
+            ("DST-PORT,9000-8000,direct", "invalid port 9000-8000"),
+        ];
+        for &(line, msg) in errors.iter() {
+            assert_eq!(Rule::parse(line).err(), Some(msg.to_string()), "{}", line);
+        }
+        assert!(Rule::parse("DOMAIN-REGEX,(,proxy").unwrap_err().starts_with("invalid regex"));
+    }
+
+    #[test]
+    fn matchers() {
+        let suffix = Rule::parse("DOMAIN-SUFFIX,example.com,direct").unwrap();
+        assert!(suffix.is_match(&host("example.com", 80)));
+        assert!(suffix.is_match(&host("WWW.Example.com", 80)));
+        assert!(!suffix.is_match(&host("badexample.com", 80)));
+        let cidr = Rule::parse("IP-CIDR,10.0.0.0/8,direct").unwrap();
+        assert!(cidr.is_match(&host("10.1.2.3", 80)));
+        assert!(!cidr.is_match(&host("11.1.2.3", 80)));
+        let cidr6 = Rule::parse("IP-CIDR6,fd00::/8,direct").unwrap();
+        assert!(cidr6.is_match(&host("[fd00::1]", 80)));
+        let port = Rule::parse("DST-PORT,8000-9000,direct").unwrap();
+        assert!(port.is_match(&host("example.com", 8000)));
+        assert!(!port.is_match(&host("example.com", 9001)));
+    }
+
+    #[test]
+    fn first_rule_wins() {
+        let router = Router::new();
+        router.set_rules(vec![
+            Rule::parse("DOMAIN,ads.example.com,reject").unwrap(),
+            Rule::parse("DOMAIN-SUFFIX,example.com,direct").unwrap(),
+        ]).unwrap();
+        assert_eq!(router.route(&host("ads.example.com", 443)), Action::Reject);
+        assert_eq!(router.route(&host("www.example.com", 443)), Action::Direct);
+        assert_eq!(router.route(&host("other.org", 443)), Action::Proxy);
+    }
+}
@@ -2,55 +2,155 @@ use define::ErrCode;
 use define::ErrCode::*;
 
 use std::thread;
+use std::io::ErrorKind;
+use std::time::Duration;
 use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
 
 use local::protocol::Protocol;
+use local::balance::Balancer;
+use local::rule::Router;
+use helper::Tracker;
+use metrics::Metrics;
+use access::AccessLog;
+use limit::Limiter;
+use config::LimitConfig;
+use signal;
+use signal::Event;
 
 pub struct LocalServer {
     ip: String,
     port: u32,
     listener: TcpListener,
     time_out: u64,
-    remote_ip: String,
-    remote_port: u32,
+    balancer: Balancer,
+    router: Router,
+    connect_status: bool,
+    drain_time_out: u64,
+    tracker: Tracker,
+    metrics: Metrics,
+    access_log: AccessLog,
+    limiter: Limiter,
 }
 
 impl LocalServer {
 
-    pub fn new(ip:&str, port:u32, remote_ip:&str, remote_port:u32, time_out:u64) -> Result<Self, ErrCode> {
+    pub fn new(ip:&str, port:u32, balancer:Balancer, time_out:u64) -> Result<Self, ErrCode> {
         let url = format!("{}:{}", ip, port);
         let listener = TcpListener::bind(&url).or_else(|e|{
             error!("{}", e);
             Err(UrlErr)
         })?;
+        //accepted after signal::wait, a connection reset in between does not block the loop
+        let _ = listener.set_nonblocking(true).or(Err(SocketErr))?;
         Ok(LocalServer {
             ip: ip.to_string(),
             port: port,
             listener: listener,
             time_out: time_out,
-            remote_ip: remote_ip.to_string(),
-            remote_port: remote_port,
+            balancer: balancer,
+            router: Router::new(),
+            connect_status: false,
+            drain_time_out: 30,
+            tracker: Tracker::new(),
+            metrics: Metrics::new(),
+            access_log: AccessLog::new(),
+            limiter: Limiter::new(),
         })
     }
 
-    //开启监听
-    pub fn start(&mut self) {
+    ///wait for the status frame of the server before answering the client
+    pub fn set_connect_status(&mut self, connect_status:bool) {
+        self.connect_status = connect_status;
+    }
+
+    ///listen on the new address, the old listener is kept if the bind fails
+    pub fn rebind(&mut self, ip:&str, port:u32) -> Result<(), ErrCode> {
+        if ip == self.ip && port == self.port {
+            return Ok(());
+        }
+        let url = format!("{}:{}", ip, port);
+        let listener = TcpListener::bind(&url).or_else(|e|{
+            error!("{}", e);
+            Err(UrlErr)
+        })?;
+        let _ = listener.set_nonblocking(true).or(Err(SocketErr))?;
+        info!("local server rebind from {}:{} to {}", self.ip, self.port, url);
+        self.listener = listener;
+        self.ip = ip.to_string();
+        self.port = port;
+        Ok(())
+    }
+
+    pub fn set_time_out(&mut self, time_out:u64) {
+        self.time_out = time_out;
+    }
+
+    pub fn balancer(&self) -> &Balancer {
+        &self.balancer
+    }
+
+    pub fn router(&self) -> &Router {
+        &self.router
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    ///append a json line for every session to the file, reopened on every call
+    pub fn set_access_log(&mut self, path:Option<&str>) -> Result<(), ErrCode> {
+        self.access_log.set_path(path)
+    }
+
+    ///the bandwidth of the new tunnels, the user is the ip of the client
+    pub fn set_limits(&mut self, limits:LimitConfig) {
+        self.limiter.set_config(limits);
+    }
+
+    ///seconds to wait for the active tunnels on shutdown
+    pub fn set_drain_time_out(&mut self, drain_time_out:u64) {
+        self.drain_time_out = drain_time_out;
+    }
+
+    //开启监听, return after SIGTERM/SIGINT and the tunnels are drained, or on SIGHUP
+    pub fn start(&mut self) -> Event {
         info!("local server start listening on {}:{}", self.ip, self.port);
-        for stream_rst in self.listener.incoming() {
-            let time_out = self.time_out;
-            let remote_port = self.remote_port;
-            if let Ok(stream) = stream_rst {
-                let _ = Self::handle_stream(stream, &self.remote_ip, remote_port, time_out);
+        while !signal::is_terminated() {
+            if signal::take_reload() {
+                return Event::Reload;
+            }
+            if signal::wait(&[self.listener.as_raw_fd()]).is_empty() {
+                continue;
+            }
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    let _ = self.handle_stream(stream);
+                },
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {},
+                Err(e) => {
+                    error!("{}", e);
+                },
             }
         }
+        info!("local server stop accepting, {} tunnels active", self.tracker.len());
+        let count = self.tracker.drain(Duration::from_secs(self.drain_time_out));
+        info!("local server stopped, {} tunnels force-closed", count);
+        Event::Terminate
     }
 
-    pub fn handle_stream(stream:TcpStream, remote_ip:&str, remote_port:u32, time_out:u64) -> Result<(), ErrCode> {
+    ///serve the stream in its own thread with the current settings
+    fn handle_stream(&self, stream:TcpStream) -> Result<(), ErrCode> {
         let peer_addr = stream.peer_addr().or(Err(SocketErr))?;
         info!("{}", peer_addr);
-        let ip = remote_ip.to_string();
+        let _ = stream.set_nonblocking(false).or(Err(SocketErr))?;
+        let guard = self.tracker.register(&stream)?;
+        let conn = self.metrics.open(None);
+        let session = self.access_log.open(Some(peer_addr), None);
+        let mut pro = Protocol::new(stream, self.balancer.clone(), self.router.clone(), self.time_out, self.connect_status, conn, session);
+        pro.set_throttle(self.limiter.open(Some(&peer_addr.ip().to_string())));
         let _ = thread::spawn(move|| {
-            let mut pro = Protocol::new(stream, ip, remote_port, time_out);
+            let _guard = guard;
             let _ = pro.start();
         });
         Ok(())
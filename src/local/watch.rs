@@ -0,0 +1,63 @@
+use define::ErrCode;
+use define::ErrCode::*;
+
+use std::fs;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use signal;
+
+///reloads the config when one of the files is modified
+#[derive(Debug, Clone)]
+pub struct Watcher {
+    files: Arc<RwLock<Vec<(String, Option<SystemTime>)>>>,
+}
+
+fn modified(path:&str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+impl Watcher {
+
+    pub fn new() -> Self {
+        Watcher {
+            files: Arc::new(RwLock::new(Vec::new())),
+        }
+    }
+
+    ///the current modification times are the base of the next checks
+    pub fn set_files(&self, paths:&[String]) -> Result<(), ErrCode> {
+        let files = paths.iter().map(|path| (path.clone(), modified(path))).collect();
+        *self.files.write().or(Err(LockErr))? = files;
+        Ok(())
+    }
+
+    ///take the current modification times of the watched files
+    fn refresh(&self) -> Result<(), ErrCode> {
+        let mut files = self.files.write().or(Err(LockErr))?;
+        for file in files.iter_mut() {
+            file.1 = modified(&file.0);
+        }
+        Ok(())
+    }
+
+    pub fn start(&self, interval:Duration) {
+        let watcher = self.clone();
+        let _ = thread::spawn(move || {
+            loop {
+                thread::sleep(interval);
+                let changed = match watcher.files.read() {
+                    Ok(files) => files.iter().find(|&&(ref path, time)| modified(path) != time).map(|f| f.0.clone()),
+                    Err(_) => None,
+                };
+                if let Some(path) = changed {
+                    info!("{} is modified, reload", path);
+                    //keep watching the same files when the new config is invalid, a successful reload replaces them
+                    let _ = watcher.refresh();
+                    signal::reload();
+                }
+            }
+        });
+    }
+}
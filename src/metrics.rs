@@ -0,0 +1,383 @@
+//! prometheus metrics of sslocal and ssserver, the text format over http.
+//! the connection counters are kept here, the other values are appended by
+//! the collectors the binaries register, like the dns cache or the upstreams.
+
+use define::{ErrCode, Reply};
+use define::ErrCode::*;
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+///upper bounds of the connect latency buckets, in milliseconds
+const BUCKETS:&[u64] = &[5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+///appends its metric families to the page
+pub type Collector = Box<dyn Fn(&mut String) + Send + Sync>;
+
+///bytes of the tunnels, up is from the client to the target
+#[derive(Debug, Default)]
+pub struct Traffic {
+    up: AtomicU64,
+    down: AtomicU64,
+}
+
+struct Inner {
+    active: AtomicU64,
+    total: AtomicU64,
+    handshake_failures: Mutex<BTreeMap<String, u64>>, //by the ErrCode
+    connect_failures: Mutex<BTreeMap<String, u64>>, //by the socks5 reply
+    traffic: Traffic,
+    users: Mutex<BTreeMap<String, Arc<Traffic>>>,
+    buckets: Vec<AtomicU64>, //not cumulative, the last one is +Inf
+    latency_sum: AtomicU64, //microseconds
+    latency_count: AtomicU64,
+    collectors: RwLock<Vec<Collector>>,
+}
+
+#[derive(Clone)]
+pub struct Metrics {
+    inner: Arc<Inner>,
+}
+
+///one accepted connection, active until it is dropped
+pub struct Connection {
+    metrics: Metrics,
+    user: Option<Arc<Traffic>>,
+    session: Arc<Traffic>, //the bytes of this connection alone
+}
+
+impl Drop for Connection {
+
+    fn drop(&mut self) {
+        self.metrics.inner.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+///counts the bytes of one tunnel, shared by its two threads
+#[derive(Clone)]
+pub struct Meter {
+    metrics: Metrics,
+    user: Option<Arc<Traffic>>,
+    session: Arc<Traffic>,
+}
+
+impl Meter {
+
+    pub fn up(&self, size:usize) {
+        self.metrics.inner.traffic.up.fetch_add(size as u64, Ordering::Relaxed);
+        self.session.up.fetch_add(size as u64, Ordering::Relaxed);
+        if let Some(ref user) = self.user {
+            user.up.fetch_add(size as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub fn down(&self, size:usize) {
+        self.metrics.inner.traffic.down.fetch_add(size as u64, Ordering::Relaxed);
+        self.session.down.fetch_add(size as u64, Ordering::Relaxed);
+        if let Some(ref user) = self.user {
+            user.down.fetch_add(size as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Connection {
+
+    pub fn meter(&self) -> Meter {
+        Meter {
+            metrics: self.metrics.clone(),
+            user: self.user.clone(),
+            session: self.session.clone(),
+        }
+    }
+
+    ///the bytes up and down of this connection
+    pub fn bytes(&self) -> (u64, u64) {
+        (self.session.up.load(Ordering::Relaxed), self.session.down.load(Ordering::Relaxed))
+    }
+
+    ///closed before the tunnel
+    pub fn handshake_failure(&self, e:ErrCode) {
+        add(&self.metrics.inner.handshake_failures, &format!("{:?}", e));
+    }
+
+    pub fn connect_failure(&self, rep:Reply) {
+        add(&self.metrics.inner.connect_failures, &format!("{:?}", rep));
+    }
+
+    ///the time to reach the target, through the upstream on sslocal
+    pub fn connected(&self, elapsed:Duration) {
+        let us = elapsed.as_secs() * 1_000_000 + elapsed.subsec_micros() as u64;
+        let inner = &self.metrics.inner;
+        let pos = BUCKETS.iter().position(|&bound| us <= bound * 1000).unwrap_or(BUCKETS.len());
+        inner.buckets[pos].fetch_add(1, Ordering::Relaxed);
+        inner.latency_sum.fetch_add(us, Ordering::Relaxed);
+        inner.latency_count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn add(map:&Mutex<BTreeMap<String, u64>>, key:&str) {
+    if let Ok(mut map) = map.lock() {
+        *map.entry(key.to_string()).or_insert(0) += 1;
+    }
+}
+
+///a label pair, the value escaped
+pub fn label(name:&str, value:&str) -> String {
+    let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+    format!("{}=\"{}\"", name, value)
+}
+
+///one family, the samples are the labels without braces and the value
+pub fn family<T:Display>(out:&mut String, name:&str, kind:&str, help:&str, samples:&[(String, T)]) {
+    out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+    for &(ref labels, ref value) in samples.iter() {
+        if labels.is_empty() {
+            out.push_str(&format!("{} {}\n", name, value));
+        } else {
+            out.push_str(&format!("{}{{{}}} {}\n", name, labels, value));
+        }
+    }
+}
+
+impl Metrics {
+
+    pub fn new() -> Self {
+        let inner = Inner {
+            active: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+            handshake_failures: Mutex::new(BTreeMap::new()),
+            connect_failures: Mutex::new(BTreeMap::new()),
+            traffic: Default::default(),
+            users: Mutex::new(BTreeMap::new()),
+            buckets: (0..BUCKETS.len() + 1).map(|_| AtomicU64::new(0)).collect(),
+            latency_sum: AtomicU64::new(0),
+            latency_count: AtomicU64::new(0),
+            collectors: RwLock::new(Vec::new()),
+        };
+        Metrics {
+            inner: Arc::new(inner),
+        }
+    }
+
+    ///the user counts the traffic of the connection on its own too
+    pub fn open(&self, user:Option<&str>) -> Connection {
+        self.inner.total.fetch_add(1, Ordering::Relaxed);
+        self.inner.active.fetch_add(1, Ordering::Relaxed);
+        let user = user.and_then(|user| {
+            let mut users = self.inner.users.lock().ok()?;
+            Some(users.entry(user.to_string()).or_insert_with(Default::default).clone())
+        });
+        Connection {
+            metrics: self.clone(),
+            user: user,
+            session: Default::default(),
+        }
+    }
+
+    ///the bytes up and down of the user since the start
+    pub fn user_bytes(&self, user:&str) -> (u64, u64) {
+        let users = match self.inner.users.lock() {
+            Ok(users) => users,
+            Err(_) => return (0, 0),
+        };
+        users.get(user).map(|traffic| (traffic.up.load(Ordering::Relaxed), traffic.down.load(Ordering::Relaxed))).unwrap_or((0, 0))
+    }
+
+    pub fn register<F>(&self, collector:F) where F: Fn(&mut String) + Send + Sync + 'static {
+        if let Ok(mut collectors) = self.inner.collectors.write() {
+            collectors.push(Box::new(collector));
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let inner = &self.inner;
+        let mut out = String::new();
+        family(&mut out, "ss_connections_active", "gauge", "connections being served",
+            &[(String::new(), inner.active.load(Ordering::Relaxed))]);
+        family(&mut out, "ss_connections_total", "counter", "accepted connections",
+            &[(String::new(), inner.total.load(Ordering::Relaxed))]);
+        let failures:Vec<(String, u64)> = inner.handshake_failures.lock().map(|map| {
+            map.iter().map(|(e, count)| (label("error", e), *count)).collect()
+        }).unwrap_or_default();
+        family(&mut out, "ss_handshake_failures_total", "counter", "connections closed before the tunnel, by the error", &failures);
+        let failures:Vec<(String, u64)> = inner.connect_failures.lock().map(|map| {
+            map.iter().map(|(rep, count)| (label("reply", rep), *count)).collect()
+        }).unwrap_or_default();
+        family(&mut out, "ss_connect_failures_total", "counter", "targets which could not be reached, by the socks5 reply", &failures);
+        family(&mut out, "ss_bytes_total", "counter", "tunneled bytes, up is from the client to the target", &[
+            (label("direction", "up"), inner.traffic.up.load(Ordering::Relaxed)),
+            (label("direction", "down"), inner.traffic.down.load(Ordering::Relaxed)),
+        ]);
+        let users:Vec<(String, u64)> = inner.users.lock().map(|users| {
+            users.iter().flat_map(|(user, traffic)| vec![
+                (format!("{},{}", label("user", user), label("direction", "up")), traffic.up.load(Ordering::Relaxed)),
+                (format!("{},{}", label("user", user), label("direction", "down")), traffic.down.load(Ordering::Relaxed)),
+            ]).collect()
+        }).unwrap_or_default();
+        if !users.is_empty() {
+            family(&mut out, "ss_user_bytes_total", "counter", "tunneled bytes of every user", &users);
+        }
+        let mut buckets = Vec::new();
+        let mut count = 0;
+        for (i, bucket) in inner.buckets.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+            let le = match BUCKETS.get(i) {
+                Some(&ms) => format!("{}", ms as f64 / 1000.0),
+                None => "+Inf".to_string(),
+            };
+            buckets.push((label("le", &le), count));
+        }
+        let name = "ss_connect_duration_seconds";
+        out.push_str(&format!("# HELP {} time to reach the target\n# TYPE {} histogram\n", name, name));
+        for &(ref labels, count) in buckets.iter() {
+            out.push_str(&format!("{}_bucket{{{}}} {}\n", name, labels, count));
+        }
+        out.push_str(&format!("{}_sum {}\n", name, inner.latency_sum.load(Ordering::Relaxed) as f64 / 1_000_000.0));
+        out.push_str(&format!("{}_count {}\n", name, inner.latency_count.load(Ordering::Relaxed)));
+        if let Ok(collectors) = inner.collectors.read() {
+            for collector in collectors.iter() {
+                collector(&mut out);
+            }
+        }
+        out
+    }
+
+    ///serve GET /metrics in the background
+    pub fn serve(&self, ip:&str, port:u32) -> Result<(), ErrCode> {
+        let url = format!("{}:{}", ip, port);
+        let listener = TcpListener::bind(&url).or_else(|e|{
+            error!("{}", e);
+            Err(UrlErr)
+        })?;
+        info!("metrics start listening on {}, http://{}/metrics", url, url);
+        let metrics = self.clone();
+        let _ = thread::spawn(move || {
+            for stream_rst in listener.incoming() {
+                if let Ok(stream) = stream_rst {
+                    let metrics = metrics.clone();
+                    let _ = thread::spawn(move || {
+                        let _ = metrics.handle_stream(stream);
+                    });
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn handle_stream(&self, mut stream:TcpStream) -> Result<(), ErrCode> {
+        let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+        let _ = stream.set_write_timeout(Some(Duration::from_secs(5)));
+        let mut head = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+            let size = stream.read(&mut buf).or(Err(SocketErr))?;
+            if size == 0 || head.len() > 8192 {
+                return Err(SocketErr);
+            }
+            head.extend_from_slice(&buf[..size]);
+        }
+        let head = String::from_utf8_lossy(&head);
+        let mut parts = head.lines().next().unwrap_or("").split_whitespace();
+        let method = parts.next().unwrap_or("");
+        let path = parts.next().unwrap_or("").split('?').next().unwrap_or("");
+
+        let (status, body) = if method != "GET" && method != "HEAD" {
+            ("405 Method Not Allowed", String::new())
+        } else if path != "/" && path != "/metrics" {
+            ("404 Not Found", String::new())
+        } else {
+            ("200 OK", self.render())
+        };
+        let mut response = format!("HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status, body.len());
+        if method != "HEAD" {
+            response.push_str(&body);
+        }
+        let _ = stream.write_all(response.as_bytes()).or(Err(SocketErr))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(out:&str, prefix:&str) -> Vec<String> {
+        out.lines().filter(|line| line.starts_with(prefix)).map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn labels_are_escaped() {
+        assert_eq!(label("user", "alice"), r#"user="alice""#);
+        assert_eq!(label("user", "a\"b\\c\nd"), r#"user="a\"b\\c\nd""#);
+    }
+
+    #[test]
+    fn family_format() {
+        let mut out = String::new();
+        family(&mut out, "ss_test", "gauge", "a test", &[(String::new(), 1), (label("k", "v"), 2)]);
+        family::<u64>(&mut out, "ss_empty", "counter", "no samples", &[]);
+        assert_eq!(out, "# HELP ss_test a test\n# TYPE ss_test gauge\nss_test 1\nss_test{k=\"v\"} 2\n\
+            # HELP ss_empty no samples\n# TYPE ss_empty counter\n");
+    }
+
+    #[test]
+    fn histogram_is_cumulative() {
+        let metrics = Metrics::new();
+        let conn = metrics.open(None);
+        for &ms in [3, 5, 7, 300, 20000].iter() {
+            conn.connected(Duration::from_millis(ms));
+        }
+        let out = metrics.render();
+        assert_eq!(lines(&out, "ss_connect_duration_seconds"), vec![
+            "ss_connect_duration_seconds_bucket{le=\"0.005\"} 2",
+            "ss_connect_duration_seconds_bucket{le=\"0.01\"} 3",
+            "ss_connect_duration_seconds_bucket{le=\"0.025\"} 3",
+            "ss_connect_duration_seconds_bucket{le=\"0.05\"} 3",
+            "ss_connect_duration_seconds_bucket{le=\"0.1\"} 3",
+            "ss_connect_duration_seconds_bucket{le=\"0.25\"} 3",
+            "ss_connect_duration_seconds_bucket{le=\"0.5\"} 4",
+            "ss_connect_duration_seconds_bucket{le=\"1\"} 4",
+            "ss_connect_duration_seconds_bucket{le=\"2.5\"} 4",
+            "ss_connect_duration_seconds_bucket{le=\"5\"} 4",
+            "ss_connect_duration_seconds_bucket{le=\"10\"} 4",
+            "ss_connect_duration_seconds_bucket{le=\"+Inf\"} 5",
+            "ss_connect_duration_seconds_sum 20.315",
+            "ss_connect_duration_seconds_count 5",
+        ]);
+        assert!(out.contains("# TYPE ss_connect_duration_seconds histogram\n"));
+    }
+
+    #[test]
+    fn counters() {
+        let metrics = Metrics::new();
+        metrics.register(|out:&mut String| out.push_str("ss_collected 7\n"));
+        let first = metrics.open(Some("al\"ice"));
+        let second = metrics.open(None);
+        first.meter().up(10);
+        first.meter().down(100);
+        second.meter().up(1);
+        first.handshake_failure(SocketErr);
+        first.handshake_failure(SocketErr);
+        second.connect_failure(Reply::HostUnreachable);
+        drop(second);
+        let out = metrics.render();
+        assert_eq!(lines(&out, "ss_connections"), vec!["ss_connections_active 1", "ss_connections_total 2"]);
+        assert_eq!(lines(&out, "ss_handshake_failures_total"), vec!["ss_handshake_failures_total{error=\"SocketErr\"} 2"]);
+        assert_eq!(lines(&out, "ss_connect_failures_total"), vec!["ss_connect_failures_total{reply=\"HostUnreachable\"} 1"]);
+        assert_eq!(lines(&out, "ss_bytes_total"), vec!["ss_bytes_total{direction=\"up\"} 11", "ss_bytes_total{direction=\"down\"} 100"]);
+        assert_eq!(lines(&out, "ss_user_bytes_total"), vec![
+            "ss_user_bytes_total{user=\"al\\\"ice\",direction=\"up\"} 10",
+            "ss_user_bytes_total{user=\"al\\\"ice\",direction=\"down\"} 100",
+        ]);
+        assert_eq!(first.bytes(), (10, 100));
+        assert_eq!(metrics.user_bytes("al\"ice"), (10, 100));
+        assert!(out.ends_with("ss_collected 7\n"));
+    }
+}
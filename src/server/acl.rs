@@ -0,0 +1,223 @@
+//! the targets ssserver may connect, checked in this order:
+//!
+//!   deny.ports, then allow.ports if it is not empty
+//!   deny.domains, an allowed domain skips the address checks
+//!   allow.cidrs, deny.cidrs, then the private networks with block_private
+//!
+//! the addresses are checked after the dns resolution, so a domain which
+//! resolves to a private address is denied too. an ipv6 address carrying an
+//! ipv4 one (mapped, compatible, nat64 or 6to4) is checked as both. a listener
+//! may have its own acl, the target must pass the global one and the one of
+//! its listener.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use config::{AclConfig, AclList};
+use helper::{cidr_contains, parse_cidr};
+
+///blocked by block_private
+const PRIVATE:&[(&str, &str)] = &[
+    ("0.0.0.0/8", "this network"),
+    ("10.0.0.0/8", "private"),
+    ("100.64.0.0/10", "shared address space"),
+    ("127.0.0.0/8", "loopback"),
+    ("169.254.0.0/16", "link-local"),
+    ("172.16.0.0/12", "private"),
+    ("192.0.0.0/24", "ietf protocol assignments"),
+    ("192.168.0.0/16", "private"),
+    ("198.18.0.0/15", "benchmarking"),
+    ("224.0.0.0/4", "multicast"),
+    ("240.0.0.0/4", "reserved"),
+    ("::/128", "unspecified"),
+    ("::1/128", "loopback"),
+    ("64:ff9b:1::/48", "local-use nat64"),
+    ("fc00::/7", "unique local"),
+    ("fe80::/10", "link-local"),
+    ("fec0::/10", "site-local"),
+    ("ff00::/8", "multicast"),
+];
+
+fn domain_match(domains:&[String], domain:&str) -> Option<String> {
+    let domain = domain.trim_end_matches('.').to_lowercase();
+    domains.iter().find(|d| domain == **d || domain.ends_with(&format!(".{}", d))).cloned()
+}
+
+fn port_match(ports:&[(u16, u16)], port:u16) -> Option<String> {
+    ports.iter().find(|&&(low, high)| port >= low && port <= high).map(|&(low, high)| {
+        if low == high { low.to_string() } else { format!("{}-{}", low, high) }
+    })
+}
+
+///the ipv4 address inside ::ffff:0:0/96 (mapped), ::/96 (compatible),
+///64:ff9b::/96 (nat64) or 2002::/16 (6to4)
+fn embedded_ipv4(ip:&Ipv6Addr) -> Option<Ipv4Addr> {
+    let s = ip.segments();
+    let v4 = |high:u16, low:u16| Ipv4Addr::new((high >> 8) as u8, high as u8, (low >> 8) as u8, low as u8);
+    match (s[0], s[1], s[2], s[3], s[4], s[5]) {
+        (0, 0, 0, 0, 0, 0xffff) | (0, 0, 0, 0, 0, 0) | (0x64, 0xff9b, 0, 0, 0, 0) => Some(v4(s[6], s[7])),
+        (0x2002, _, _, _, _, _) => Some(v4(s[1], s[2])),
+        _ => None,
+    }
+}
+
+///the address and the ipv4 address inside it
+fn addresses(ip:&IpAddr) -> Vec<IpAddr> {
+    let mut ips = vec![*ip];
+    if let IpAddr::V6(ref v6) = *ip {
+        ips.extend(embedded_ipv4(v6).map(IpAddr::V4));
+    }
+    ips
+}
+
+fn cidr_match(list:&AclList, ip:&IpAddr) -> Option<String> {
+    addresses(ip).iter().filter_map(|ip| {
+        list.cidrs.iter().find(|&&(ref net, len)| cidr_contains(net, len, ip)).map(|&(ref net, len)| format!("{}/{}", net, len))
+    }).next()
+}
+
+///the private network of the address or of the ipv4 address inside it
+pub fn private_network(ip:&IpAddr) -> Option<String> {
+    for ip in addresses(ip) {
+        for &(cidr, name) in PRIVATE {
+            if let Some((net, len)) = parse_cidr(cidr) {
+                if cidr_contains(&net, len, &ip) {
+                    return Some(format!("{} {}", name, cidr));
+                }
+            }
+        }
+    }
+    None
+}
+
+#[derive(Debug, Clone)]
+pub struct Acl {
+    name: String, //the config key, names the denying rule in the log
+    cfg: AclConfig,
+}
+
+impl Acl {
+
+    pub fn new(name:&str, cfg:AclConfig) -> Self {
+        Acl {
+            name: name.to_string(),
+            cfg: cfg,
+        }
+    }
+
+    ///the domain is none for an address request, the ip is none before the resolution.
+    ///Err names the rule which denied the target
+    pub fn check(&self, domain:Option<&str>, ip:Option<&IpAddr>, port:u16) -> Result<(), String> {
+        let cfg = &self.cfg;
+        if let Some(ports) = port_match(&cfg.deny.ports, port) {
+            return Err(format!("{}.deny.ports {}", self.name, ports));
+        }
+        if !cfg.allow.ports.is_empty() && port_match(&cfg.allow.ports, port).is_none() {
+            return Err(format!("{}.allow.ports, {} is not listed", self.name, port));
+        }
+        if let Some(domain) = domain {
+            if let Some(rule) = domain_match(&cfg.deny.domains, domain) {
+                return Err(format!("{}.deny.domains {}", self.name, rule));
+            }
+            if domain_match(&cfg.allow.domains, domain).is_some() {
+                return Ok(());
+            }
+        }
+        let ip = match ip {
+            Some(ip) => ip,
+            None => return Ok(()),
+        };
+        if cidr_match(&cfg.allow, ip).is_some() {
+            return Ok(());
+        }
+        if let Some(rule) = cidr_match(&cfg.deny, ip) {
+            return Err(format!("{}.deny.cidrs {}", self.name, rule));
+        }
+        if cfg.block_private {
+            if let Some(network) = private_network(ip) {
+                return Err(format!("{}.block_private, {} is {}", self.name, ip, network));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip:&str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn cidrs(cidrs:&[&str]) -> Vec<(IpAddr, u8)> {
+        cidrs.iter().map(|cidr| parse_cidr(cidr).unwrap()).collect()
+    }
+
+    fn strings(values:&[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn private_networks() {
+        for private in ["0.0.0.0", "10.1.2.3", "100.64.0.1", "127.0.0.1", "169.254.169.254", "172.31.255.255", "192.168.1.1",
+                        "198.19.0.1", "224.0.0.1", "255.255.255.255", "::", "::1", "fd00::1", "fe80::1", "fec0::1", "ff02::1",
+                        "64:ff9b:1::1"].iter() {
+            assert!(private_network(&ip(private)).is_some(), "{}", private);
+        }
+        for public in ["8.8.8.8", "1.1.1.1", "172.32.0.1", "100.128.0.1", "2001:4860:4860::8888", "64:ff9b::808:808",
+                       "2002:808:808::1", "::ffff:8.8.8.8"].iter() {
+            assert_eq!(private_network(&ip(public)), None, "{}", public);
+        }
+    }
+
+    #[test]
+    fn private_inside_ipv6() {
+        //mapped, compatible, nat64 and 6to4
+        assert_eq!(private_network(&ip("::ffff:127.0.0.1")), Some("loopback 127.0.0.0/8".to_string()));
+        assert_eq!(private_network(&ip("::ffff:a9fe:a9fe")), Some("link-local 169.254.0.0/16".to_string()));
+        assert_eq!(private_network(&ip("::10.0.0.1")), Some("private 10.0.0.0/8".to_string()));
+        assert_eq!(private_network(&ip("64:ff9b::192.168.0.1")), Some("private 192.168.0.0/16".to_string()));
+        assert_eq!(private_network(&ip("2002:7f00:1::")), Some("loopback 127.0.0.0/8".to_string()));
+        assert_eq!(private_network(&ip("2002:ac10:1:2::3")), Some("private 172.16.0.0/12".to_string()));
+        //the ipv6 networks are named first
+        assert_eq!(private_network(&ip("::1")), Some("loopback ::1/128".to_string()));
+    }
+
+    #[test]
+    fn check_order() {
+        let mut cfg:AclConfig = Default::default();
+        cfg.deny.ports = vec![(25, 25)];
+        cfg.allow.ports = vec![(25, 25), (80, 80), (443, 443)];
+        cfg.deny.domains = strings(&["bad.example.com", "blocked.test"]);
+        cfg.allow.domains = strings(&["example.com"]);
+        cfg.allow.cidrs = cidrs(&["10.1.0.0/16"]);
+        cfg.deny.cidrs = cidrs(&["10.0.0.0/8", "8.8.8.0/24"]);
+        let acl = Acl::new("acl", cfg);
+        let local = ip("10.0.0.1");
+        //the denied ports first, even for an allowed domain
+        assert_eq!(acl.check(Some("example.com"), None, 25), Err("acl.deny.ports 25".to_string()));
+        assert_eq!(acl.check(None, Some(&ip("1.1.1.1")), 22), Err("acl.allow.ports, 22 is not listed".to_string()));
+        //a denied subdomain of an allowed domain
+        assert_eq!(acl.check(Some("www.Bad.example.com."), None, 443), Err("acl.deny.domains bad.example.com".to_string()));
+        //an allowed domain skips the addresses
+        assert_eq!(acl.check(Some("intranet.example.com"), Some(&local), 443), Ok(()));
+        assert_eq!(acl.check(Some("other.test"), Some(&local), 443), Err("acl.deny.cidrs 10.0.0.0/8".to_string()));
+        //the addresses are unknown before the resolution
+        assert_eq!(acl.check(Some("other.test"), None, 443), Ok(()));
+        //allow.cidrs before deny.cidrs and block_private
+        assert_eq!(acl.check(None, Some(&ip("10.1.2.3")), 80), Ok(()));
+        assert_eq!(acl.check(None, Some(&ip("::ffff:10.1.2.3")), 80), Ok(()));
+        assert_eq!(acl.check(None, Some(&ip("64:ff9b::808:808")), 80), Err("acl.deny.cidrs 8.8.8.0/24".to_string()));
+        assert_eq!(acl.check(None, Some(&ip("192.168.1.1")), 80), Err("acl.block_private, 192.168.1.1 is private 192.168.0.0/16".to_string()));
+        assert_eq!(acl.check(None, Some(&ip("1.1.1.1")), 80), Ok(()));
+    }
+
+    #[test]
+    fn private_allowed() {
+        let mut cfg:AclConfig = Default::default();
+        cfg.block_private = false;
+        let acl = Acl::new("servers[alice].acl", cfg);
+        assert_eq!(acl.check(None, Some(&ip("127.0.0.1")), 80), Ok(()));
+        assert!(Acl::new("acl", Default::default()).check(None, Some(&ip("2002:a00:1::")), 80).is_err());
+    }
+}
@@ -1,17 +1,115 @@
-use define::{ErrCode, Ip};
+use define::ErrCode;
 use define::ErrCode::*;
 
-use std::collections::BTreeMap;
-use std::sync::{Arc, RwLock};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::IpAddr;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use config::DnsConfig;
 use helper;
+use metrics;
+use server::resolver::Resolver;
 
 #[derive(Debug)]
+struct Entry {
+    rst: Result<Vec<IpAddr>, ErrCode>, //Err is a negative entry
+    expires: Instant,
+    ttl: u64,
+    used: u64, //the key in the lru
+}
+
+#[derive(Debug, Default)]
+struct State {
+    map: HashMap<String, Entry>,
+    lru: BTreeMap<u64, String>, //least recently used first
+    tick: u64,
+}
+
+impl State {
+
+    fn remove(&mut self, url:&str) -> Option<Entry> {
+        let entry = self.map.remove(url)?;
+        let _ = self.lru.remove(&entry.used);
+        Some(entry)
+    }
+
+    fn touch(&mut self, url:&str) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(entry) = self.map.get_mut(url) {
+            let _ = self.lru.remove(&entry.used);
+            entry.used = tick;
+            let _ = self.lru.insert(tick, url.to_string());
+        }
+    }
+}
+
+///one lookup in progress, the other threads asking the same name wait for it
+#[derive(Debug, Default)]
+struct Flight {
+    rst: Mutex<Option<Result<Vec<IpAddr>, ErrCode>>>,
+    done: Condvar,
+}
+
+impl Flight {
+
+    fn wait(&self) -> Result<Vec<IpAddr>, ErrCode> {
+        let mut rst = self.rst.lock().or(Err(LockErr))?;
+        while rst.is_none() {
+            rst = self.done.wait(rst).or(Err(LockErr))?;
+        }
+        rst.clone().unwrap_or(Err(LockErr))
+    }
+
+    fn finish(&self, rst:Result<Vec<IpAddr>, ErrCode>) {
+        if let Ok(mut current) = self.rst.lock() {
+            *current = Some(rst);
+        }
+        self.done.notify_all();
+    }
+}
+
+///the addresses of a name and their ttl
+type Resolve = Arc<dyn Fn(&str) -> Result<(Vec<IpAddr>, Option<u32>), ErrCode> + Send + Sync>;
+
 struct Inner {
-    map: RwLock<BTreeMap<String, Ip>>,
+    state: Mutex<State>,
+    flights: Mutex<HashMap<String, Arc<Flight>>>,
+    cfg: RwLock<DnsConfig>,
+    resolver: RwLock<Option<Resolve>>, //none for the system resolver
+    hits: AtomicU64,
+    misses: AtomicU64,
+    expired: AtomicU64,
+    evictions: AtomicU64,
+    negative_hits: AtomicU64,
+    coalesced: AtomicU64,
+    refreshes: AtomicU64,
 }
 
-#[derive(Debug, Clone)]
+///the counters since the start
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub size: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub expired: u64, //misses of an entry past its ttl
+    pub evictions: u64, //removed for the size bound
+    pub negative_hits: u64, //hits of a failed lookup
+    pub coalesced: u64, //misses which waited for the lookup of another thread
+    pub refreshes: u64, //lookups of hot entries before they expire
+}
+
+///the resolved domains, expired by ttl and bounded by lru eviction.
+///a failed lookup is cached for negative_ttl, a name is looked up by one thread
+///at a time and a hit shortly before the expiry refreshes it in the background.
+///the pinned hosts are answered before the cache
+#[derive(Clone)]
 pub struct DnsCache {
     inner: Arc<Inner>,
 }
@@ -19,38 +117,534 @@ pub struct DnsCache {
 impl DnsCache {
 
     pub fn new() -> Self {
-        let inner = Inner {map: RwLock::new(BTreeMap::new())};
+        let inner = Inner {
+            state: Mutex::new(Default::default()),
+            flights: Mutex::new(HashMap::new()),
+            cfg: RwLock::new(Default::default()),
+            resolver: RwLock::new(None),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            expired: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            negative_hits: AtomicU64::new(0),
+            coalesced: AtomicU64::new(0),
+            refreshes: AtomicU64::new(0),
+        };
         DnsCache {
             inner: Arc::new(inner),
         }
     }
 
-    pub fn get_ip(&mut self, url:&str) -> Result<Ip, ErrCode> {
-        let ip_op = {
-            let map = self.inner.map.read().or(Err(LockErr))?;
-            let ip_op = map.get(url);
-            if let Some(ip) = ip_op {
-                Some(ip.clone())
-            } else {
-                None
+    ///a smaller size evicts on the next insert
+    pub fn set_config(&self, cfg:DnsConfig) -> Result<(), ErrCode> {
+        let resolver:Option<Resolve> = if cfg.nameservers.is_empty() {
+            None
+        } else {
+            let resolver = Resolver::new(cfg.nameservers.clone(), Duration::from_secs(cfg.timeout), cfg.retries);
+            Some(Arc::new(move |name:&str| resolver.resolve(name)))
+        };
+        *self.inner.resolver.write().or(Err(LockErr))? = resolver;
+        *self.inner.cfg.write().or(Err(LockErr))? = cfg;
+        Ok(())
+    }
+
+    ///every address of the domain, in the order of the resolver
+    pub fn get_ips(&mut self, url:&str) -> Result<Vec<IpAddr>, ErrCode> {
+        let name = normalize(url);
+        let url = name.as_str();
+        let refresh_before = {
+            let cfg = self.inner.cfg.read().or(Err(LockErr))?;
+            if let Some(ips) = cfg.hosts.get(url) {
+                return Ok(ips.clone());
+            }
+            cfg.refresh_before
+        };
+        let mut hit = None;
+        {
+            let mut state = self.inner.state.lock().or(Err(LockErr))?;
+            let now = Instant::now();
+            let cached = state.map.get(url).map(|entry| (entry.rst.clone(), entry.expires, entry.ttl));
+            match cached {
+                Some((rst, expires, ttl)) if expires > now => {
+                    state.touch(url);
+                    if rst.is_err() {
+                        self.inner.negative_hits.fetch_add(1, Ordering::Relaxed);
+                        return rst;
+                    }
+                    self.inner.hits.fetch_add(1, Ordering::Relaxed);
+                    //the window is at most half of the ttl
+                    let window = Duration::from_secs(refresh_before.min(ttl / 2));
+                    hit = Some((rst, expires - now <= window));
+                },
+                Some(_) => {
+                    let _ = state.remove(url);
+                    self.inner.expired.fetch_add(1, Ordering::Relaxed);
+                },
+                None => {},
+            }
+        }
+        if let Some((rst, refresh)) = hit {
+            if refresh {
+                self.refresh(url);
+            }
+            return rst;
+        }
+        self.inner.misses.fetch_add(1, Ordering::Relaxed);
+        self.lookup(url, false)
+    }
+
+    ///in the background, unless the name is already being looked up
+    fn refresh(&self, url:&str) {
+        let busy = self.inner.flights.lock().map(|flights| flights.contains_key(url)).unwrap_or(true);
+        if busy {
+            return;
+        }
+        self.inner.refreshes.fetch_add(1, Ordering::Relaxed);
+        let cache = self.clone();
+        let url = url.to_string();
+        let _ = thread::spawn(move || {
+            let _ = cache.lookup(&url, true);
+        });
+    }
+
+    ///single flight, the first thread resolves and the others wait for its result.
+    ///a failed refresh keeps the current entry
+    fn lookup(&self, url:&str, refresh:bool) -> Result<Vec<IpAddr>, ErrCode> {
+        let (flight, leader) = {
+            let mut flights = self.inner.flights.lock().or(Err(LockErr))?;
+            match flights.get(url) {
+                Some(flight) => (flight.clone(), false),
+                None => {
+                    let flight:Arc<Flight> = Default::default();
+                    let _ = flights.insert(url.to_string(), flight.clone());
+                    (flight, true)
+                },
             }
         };
-        if let Some(ip) = ip_op {
-            return Ok(ip);
-        } else {
-            let ipv4_addr = helper::get_ip_addr(url)?;
-            let parts = ipv4_addr.octets();
-            let ip = Ip::new(parts[0], parts[1], parts[2], parts[3]);
+        if !leader {
+            self.inner.coalesced.fetch_add(1, Ordering::Relaxed);
+            return flight.wait();
+        }
+        let rst = self.resolve(url);
+        let rst = match rst {
+            Ok((ips, ttl)) => {
+                let _ = self.insert(url, Ok(ips.clone()), ttl);
+                Ok(ips)
+            },
+            Err(e) => {
+                if refresh {
+                    warn!("refresh {} failed, {}", url, e.description());
+                } else {
+                    let _ = self.insert(url, Err(e), None);
+                }
+                Err(e)
+            },
+        };
+        flight.finish(rst.clone());
+        if let Ok(mut flights) = self.inner.flights.lock() {
+            let _ = flights.remove(url);
+        }
+        rst
+    }
+
+    ///the system resolver does not tell the ttl
+    fn resolve(&self, url:&str) -> Result<(Vec<IpAddr>, Option<u32>), ErrCode> {
+        let resolver = self.inner.resolver.read().or(Err(LockErr))?.clone();
+        match resolver {
+            Some(resolver) => resolver(url),
+            None => Ok((helper::lookup(url)?, None)),
+        }
+    }
+
+    ///the ttl is bounded by min_ttl and max_ttl, none is min_ttl.
+    ///a failure is kept for negative_ttl, 0 does not cache it
+    fn insert(&self, url:&str, rst:Result<Vec<IpAddr>, ErrCode>, ttl:Option<u32>) -> Result<(), ErrCode> {
+        let cfg = self.inner.cfg.read().or(Err(LockErr))?.clone();
+        let ttl = match rst {
+            Ok(_) => ttl.map(|ttl| ttl as u64).unwrap_or(cfg.min_ttl).max(cfg.min_ttl).min(cfg.max_ttl),
+            Err(_) => cfg.negative_ttl,
+        };
+        self.store(url, rst, ttl, cfg.cache_size)
+    }
+
+    fn store(&self, url:&str, rst:Result<Vec<IpAddr>, ErrCode>, ttl:u64, cache_size:usize) -> Result<(), ErrCode> {
+        let mut state = self.inner.state.lock().or(Err(LockErr))?;
+        let _ = state.remove(url);
+        if ttl == 0 || cache_size == 0 {
+            return Ok(());
+        }
+        while state.map.len() >= cache_size && !state.lru.is_empty() {
+            let oldest = state.lru.keys().next().cloned().unwrap_or(0);
+            if let Some(url) = state.lru.remove(&oldest) {
+                let _ = state.map.remove(&url);
+                self.inner.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        state.tick += 1;
+        let tick = state.tick;
+        let entry = Entry {
+            rst: rst,
+            expires: Instant::now() + Duration::from_secs(ttl),
+            ttl: ttl,
+            used: tick,
+        };
+        let _ = state.map.insert(url.to_string(), entry);
+        let _ = state.lru.insert(tick, url.to_string());
+        Ok(())
+    }
 
-            let mut map = self.inner.map.write().or(Err(LockErr))?;
-            map.insert(url.to_string(), ip.clone());
-            Ok(ip)
+    ///write the resolved entries to cache_file, one line for each:
+    ///name, the expiry in unix seconds, the addresses. the least recently used first
+    pub fn save(&self) -> Result<usize, ErrCode> {
+        let path = match self.inner.cfg.read().or(Err(LockErr))?.cache_file.clone() {
+            Some(path) => path,
+            None => return Ok(0),
+        };
+        let lines:Vec<String> = {
+            let state = self.inner.state.lock().or(Err(LockErr))?;
+            let now = Instant::now();
+            let unix = unix_now();
+            state.lru.values().filter_map(|url| {
+                let entry = state.map.get(url)?;
+                match entry.rst {
+                    Ok(ref ips) if entry.expires > now => {
+                        let ips:Vec<String> = ips.iter().map(|ip| ip.to_string()).collect();
+                        Some(format!("{} {} {}", url, unix + (entry.expires - now).as_secs(), ips.join(" ")))
+                    },
+                    _ => None,
+                }
+            }).collect()
+        };
+        //a crash while writing keeps the previous file
+        let tmp = format!("{}.tmp", path);
+        let mut file = File::create(&tmp).or(Err(FileErr))?;
+        for line in lines.iter() {
+            let _ = writeln!(file, "{}", line).or(Err(FileErr))?;
         }
+        let _ = file.sync_all();
+        let _ = fs::rename(&tmp, &path).or(Err(FileErr))?;
+        Ok(lines.len())
+    }
+
+    ///read the entries saved by save, the expired and invalid lines are skipped.
+    ///a missing file is an empty cache
+    pub fn load(&self) -> Result<usize, ErrCode> {
+        let cfg = self.inner.cfg.read().or(Err(LockErr))?.clone();
+        let path = match cfg.cache_file {
+            Some(ref path) => path,
+            None => return Ok(0),
+        };
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+            Err(_) => return Err(FileErr),
+        };
+        let unix = unix_now();
+        let mut count = 0;
+        for line in BufReader::new(file).lines() {
+            let line = line.or(Err(FileErr))?;
+            let mut fields = line.split_whitespace();
+            let url = normalize(fields.next().unwrap_or(""));
+            let expires = fields.next().and_then(|expires| expires.parse::<u64>().ok()).unwrap_or(0);
+            let ips:Vec<IpAddr> = fields.filter_map(|ip| ip.parse().ok()).collect();
+            if url.is_empty() || expires <= unix || ips.is_empty() || cfg.hosts.contains_key(&url) {
+                continue;
+            }
+            let _ = self.store(&url, Ok(ips), (expires - unix).min(cfg.max_ttl), cfg.cache_size)?;
+            count += 1;
+        }
+        Ok(count)
     }
 
     pub fn remove(&mut self, url:&str) -> Result<(), ErrCode> {
-        let mut map = self.inner.map.write().or(Err(LockErr))?;
-        let _ = map.remove(url);
+        let mut state = self.inner.state.lock().or(Err(LockErr))?;
+        let _ = state.remove(&normalize(url));
         Ok(())
     }
+
+    ///the counters as prometheus metrics
+    pub fn collect(&self, out:&mut String) {
+        let stats = self.stats();
+        metrics::family(out, "ss_dns_cache_entries", "gauge", "entries in the dns cache", &[(String::new(), stats.size)]);
+        metrics::family(out, "ss_dns_cache_lookups_total", "counter", "lookups of the dns cache, by the result", &[
+            (metrics::label("result", "hit"), stats.hits),
+            (metrics::label("result", "miss"), stats.misses),
+            (metrics::label("result", "negative_hit"), stats.negative_hits),
+            (metrics::label("result", "coalesced"), stats.coalesced),
+        ]);
+        metrics::family(out, "ss_dns_cache_expired_total", "counter", "misses of an entry past its ttl", &[(String::new(), stats.expired)]);
+        metrics::family(out, "ss_dns_cache_evictions_total", "counter", "entries removed for the size bound", &[(String::new(), stats.evictions)]);
+        metrics::family(out, "ss_dns_cache_refreshes_total", "counter", "lookups of hot entries before they expire", &[(String::new(), stats.refreshes)]);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let size = self.inner.state.lock().map(|state| state.map.len()).unwrap_or(0);
+        CacheStats {
+            size: size,
+            hits: self.inner.hits.load(Ordering::Relaxed),
+            misses: self.inner.misses.load(Ordering::Relaxed),
+            expired: self.inner.expired.load(Ordering::Relaxed),
+            evictions: self.inner.evictions.load(Ordering::Relaxed),
+            negative_hits: self.inner.negative_hits.load(Ordering::Relaxed),
+            coalesced: self.inner.coalesced.load(Ordering::Relaxed),
+            refreshes: self.inner.refreshes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+///the key of a name, Example.COM. is example.com like in dns.hosts
+fn normalize(url:&str) -> String {
+    url.trim_end_matches('.').to_lowercase()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::path::Path;
+    use std::process;
+    use std::sync::Barrier;
+    use std::sync::atomic::{AtomicBool, AtomicUsize};
+
+    ///answers 10.0.0.n to the n-th call, or fails
+    #[derive(Default)]
+    struct Stub {
+        calls: AtomicUsize,
+        fail: AtomicBool,
+    }
+
+    fn cache(cfg:DnsConfig, delay:u64) -> (DnsCache, Arc<Stub>) {
+        let cache = DnsCache::new();
+        cache.set_config(cfg).unwrap();
+        let stub:Arc<Stub> = Default::default();
+        let counter = stub.clone();
+        let resolve:Resolve = Arc::new(move |_:&str| {
+            let n = counter.calls.fetch_add(1, Ordering::SeqCst) + 1;
+            thread::sleep(Duration::from_millis(delay));
+            if counter.fail.load(Ordering::SeqCst) {
+                return Err(UrlErr);
+            }
+            Ok((vec![IpAddr::from([10, 0, 0, n as u8])], Some(300)))
+        });
+        *cache.inner.resolver.write().unwrap() = Some(resolve);
+        (cache, stub)
+    }
+
+    fn ip(n:u8) -> Vec<IpAddr> {
+        vec![IpAddr::from([10, 0, 0, n])]
+    }
+
+    ///move the expiry of the entry
+    fn expire_in(cache:&DnsCache, url:&str, secs:u64) {
+        let mut state = cache.inner.state.lock().unwrap();
+        state.map.get_mut(url).unwrap().expires = Instant::now() + Duration::from_secs(secs);
+    }
+
+    fn expired(cache:&DnsCache, url:&str) {
+        let mut state = cache.inner.state.lock().unwrap();
+        state.map.get_mut(url).unwrap().expires = Instant::now().checked_sub(Duration::from_secs(1)).unwrap();
+    }
+
+    ///the background refresh is done
+    fn settled(cache:&DnsCache, stub:&Stub, calls:usize) {
+        for _ in 0..200 {
+            if stub.calls.load(Ordering::SeqCst) >= calls && cache.inner.flights.lock().unwrap().is_empty() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("the refresh did not finish");
+    }
+
+    #[test]
+    fn concurrent_lookups_coalesce() {
+        let (cache, stub) = cache(Default::default(), 200);
+        let barrier = Arc::new(Barrier::new(8));
+        let threads:Vec<_> = (0..8).map(|_| {
+            let mut cache = cache.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                cache.get_ips("example.com")
+            })
+        }).collect();
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), Ok(ip(1)));
+        }
+        assert_eq!(stub.calls.load(Ordering::SeqCst), 1);
+        let stats = cache.stats();
+        assert_eq!((stats.misses, stats.coalesced, stats.size), (8, 7, 1));
+        assert!(cache.inner.flights.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn one_entry_for_every_spelling() {
+        let (mut cache, stub) = cache(Default::default(), 0);
+        assert_eq!(cache.get_ips("Example.COM"), Ok(ip(1)));
+        assert_eq!(cache.get_ips("example.com."), Ok(ip(1)));
+        assert_eq!(cache.get_ips("example.com"), Ok(ip(1)));
+        assert_eq!(stub.calls.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.stats().hits, 2);
+        cache.remove("EXAMPLE.com.").unwrap();
+        assert_eq!(cache.stats().size, 0);
+    }
+
+    #[test]
+    fn failures_cached_for_negative_ttl() {
+        let (mut cache, stub) = cache(Default::default(), 0);
+        stub.fail.store(true, Ordering::SeqCst);
+        assert_eq!(cache.get_ips("nx.test"), Err(UrlErr));
+        assert_eq!(cache.get_ips("nx.test"), Err(UrlErr));
+        assert_eq!(stub.calls.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.stats().negative_hits, 1);
+        assert_eq!(cache.inner.state.lock().unwrap().map["nx.test"].ttl, 10);
+        //looked up again after negative_ttl
+        expired(&cache, "nx.test");
+        stub.fail.store(false, Ordering::SeqCst);
+        assert_eq!(cache.get_ips("nx.test"), Ok(ip(2)));
+        assert_eq!(cache.stats().expired, 1);
+
+        let mut cfg:DnsConfig = Default::default();
+        cfg.negative_ttl = 0;
+        let (mut cache, stub) = self::cache(cfg, 0);
+        stub.fail.store(true, Ordering::SeqCst);
+        assert_eq!(cache.get_ips("nx.test"), Err(UrlErr));
+        assert_eq!(cache.get_ips("nx.test"), Err(UrlErr));
+        assert_eq!(stub.calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn refresh_before_the_expiry() {
+        let (mut cache, stub) = cache(Default::default(), 0);
+        assert_eq!(cache.get_ips("example.com"), Ok(ip(1)));
+        //out of the window, no refresh
+        expire_in(&cache, "example.com", 60);
+        assert_eq!(cache.get_ips("example.com"), Ok(ip(1)));
+        assert_eq!(cache.stats().refreshes, 0);
+        //a failed refresh keeps the entry
+        expire_in(&cache, "example.com", 2);
+        stub.fail.store(true, Ordering::SeqCst);
+        assert_eq!(cache.get_ips("example.com"), Ok(ip(1)));
+        settled(&cache, &stub, 2);
+        assert_eq!(cache.inner.state.lock().unwrap().map["example.com"].rst, Ok(ip(1)));
+        assert_eq!(cache.get_ips("example.com"), Ok(ip(1)));
+        settled(&cache, &stub, 3);
+        //a successful one replaces it with a new ttl
+        stub.fail.store(false, Ordering::SeqCst);
+        assert_eq!(cache.get_ips("example.com"), Ok(ip(1)));
+        settled(&cache, &stub, 4);
+        assert_eq!(cache.get_ips("example.com"), Ok(ip(4)));
+        assert_eq!(cache.stats().refreshes, 3);
+        assert_eq!(stub.calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn lru_evicts_the_oldest() {
+        let mut cfg:DnsConfig = Default::default();
+        cfg.cache_size = 2;
+        let (mut cache, stub) = cache(cfg, 0);
+        assert_eq!(cache.get_ips("a.test"), Ok(ip(1)));
+        assert_eq!(cache.get_ips("b.test"), Ok(ip(2)));
+        //a hit makes a.test the newest
+        assert_eq!(cache.get_ips("a.test"), Ok(ip(1)));
+        assert_eq!(cache.get_ips("c.test"), Ok(ip(3)));
+        assert_eq!(cache.stats().evictions, 1);
+        assert_eq!(cache.stats().size, 2);
+        assert_eq!(cache.get_ips("a.test"), Ok(ip(1)));
+        assert_eq!(cache.get_ips("b.test"), Ok(ip(4)));
+        assert_eq!(stub.calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn hosts_are_pinned() {
+        let mut cfg:DnsConfig = Default::default();
+        let _ = cfg.hosts.insert("db.internal".to_string(), ip(9));
+        let (mut cache, stub) = cache(cfg, 0);
+        assert_eq!(cache.get_ips("DB.internal."), Ok(ip(9)));
+        assert_eq!(stub.calls.load(Ordering::SeqCst), 0);
+        assert_eq!(cache.stats().size, 0);
+    }
+
+    ///the cache file in a new directory of its own
+    fn cache_file(name:&str) -> String {
+        let dir = env::temp_dir().join(format!("ss_rust_{}_{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("dns.cache").to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn save_then_load() {
+        let path = cache_file("save");
+        let mut cfg:DnsConfig = Default::default();
+        cfg.cache_file = Some(path.clone());
+        let (mut cache, stub) = cache(cfg.clone(), 0);
+        assert_eq!(cache.get_ips("a.test"), Ok(ip(1)));
+        assert_eq!(cache.get_ips("b.test"), Ok(ip(2)));
+        assert_eq!(cache.get_ips("old.test"), Ok(ip(3)));
+        expired(&cache, "old.test");
+        //the failures are not saved
+        stub.fail.store(true, Ordering::SeqCst);
+        assert_eq!(cache.get_ips("nx.test"), Err(UrlErr));
+        assert_eq!(cache.save(), Ok(2));
+        assert!(fs::metadata(format!("{}.tmp", path)).is_err());
+
+        let content = fs::read_to_string(&path).unwrap();
+        let lines:Vec<Vec<&str>> = content.lines().map(|line| line.split(' ').collect()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!((lines[0][0], lines[0][2]), ("a.test", "10.0.0.1"));
+        assert_eq!((lines[1][0], lines[1][2]), ("b.test", "10.0.0.2"));
+        let expires:u64 = lines[0][1].parse().unwrap();
+        assert!(expires > unix_now() + 290 && expires <= unix_now() + 300);
+
+        //b.test is pinned in the new config
+        let _ = cfg.hosts.insert("b.test".to_string(), ip(9));
+        let (mut loaded, stub) = self::cache(cfg, 0);
+        assert_eq!(loaded.load(), Ok(1));
+        assert_eq!(loaded.get_ips("a.test"), Ok(ip(1)));
+        assert_eq!(loaded.get_ips("b.test"), Ok(ip(9)));
+        assert_eq!(stub.calls.load(Ordering::SeqCst), 0);
+        let ttl = loaded.inner.state.lock().unwrap().map["a.test"].ttl;
+        assert!(ttl > 290 && ttl <= 300);
+        let _ = fs::remove_dir_all(Path::new(&path).parent().unwrap());
+    }
+
+    #[test]
+    fn load_skips_invalid_lines() {
+        let path = cache_file("load");
+        let now = unix_now();
+        let lines = vec![
+            format!("a.test {} 10.0.0.1 ::1", now + 100),
+            format!("old.test {} 10.0.0.2", now - 1),
+            format!("bad.test {} nope", now + 100),
+            format!("Upper.Test. {} 10.0.0.3", now + 100),
+            format!("pinned.test {} 10.0.0.4", now + 100),
+            format!("far.test {} 10.0.0.5", now + 100000),
+            "garbage".to_string(),
+            String::new(),
+        ];
+        fs::write(&path, lines.join("\n")).unwrap();
+        let mut cfg:DnsConfig = Default::default();
+        cfg.cache_file = Some(path.clone());
+        let _ = cfg.hosts.insert("pinned.test".to_string(), ip(9));
+        let (mut cache, stub) = cache(cfg, 0);
+        assert_eq!(cache.load(), Ok(3));
+        assert_eq!(cache.get_ips("a.test"), Ok(vec![IpAddr::from([10, 0, 0, 1]), "::1".parse().unwrap()]));
+        assert_eq!(cache.get_ips("upper.test"), Ok(ip(3)));
+        assert_eq!(stub.calls.load(Ordering::SeqCst), 0);
+        //bounded by max_ttl
+        assert_eq!(cache.inner.state.lock().unwrap().map["far.test"].ttl, 3600);
+
+        //a missing file is an empty cache, without cache_file nothing is read or written
+        fs::remove_file(&path).unwrap();
+        assert_eq!(cache.load(), Ok(0));
+        let (cache, _) = self::cache(Default::default(), 0);
+        assert_eq!(cache.save(), Ok(0));
+        assert_eq!(cache.load(), Ok(0));
+        let _ = fs::remove_dir_all(Path::new(&path).parent().unwrap());
+    }
 }
@@ -0,0 +1,377 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::Read;
+use std::net::{IpAddr, TcpStream};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use config::GuardConfig;
+use metrics;
+
+#[derive(Debug, Default)]
+struct State {
+    active: HashMap<IpAddr, u64>,
+    failures: HashMap<IpAddr, VecDeque<Instant>>, //within find_time
+    bans: HashMap<IpAddr, Instant>, //until
+}
+
+impl State {
+
+    fn banned(&mut self, ip:IpAddr, now:Instant) -> bool {
+        match self.bans.get(&ip) {
+            Some(until) if *until > now => return true,
+            Some(_) => {},
+            None => return false,
+        }
+        let _ = self.bans.remove(&ip);
+        info!("{} is unbanned", ip);
+        false
+    }
+}
+
+///refused connections held open at a time, more are closed at once
+const MAX_HELD:usize = 4096;
+
+///the refused connections, closed by one thread at their deadlines
+#[derive(Debug, Default)]
+struct Held {
+    streams: Vec<(TcpStream, Instant)>,
+    running: bool, //the thread closing them
+}
+
+#[derive(Debug)]
+struct Inner {
+    cfg: RwLock<GuardConfig>,
+    state: Mutex<State>,
+    held: Mutex<Held>,
+    wake: Condvar, //a new held connection
+    banned: AtomicU64, //connections refused for a ban
+    limited: AtomicU64, //connections refused for max_connections_per_ip
+    bans: AtomicU64,
+}
+
+///the connections of every client ip, an ip with too many failed handshakes
+///is banned for ban_time. the bans are kept over the reloads
+#[derive(Debug, Clone)]
+pub struct Guard {
+    inner: Arc<Inner>,
+}
+
+///one admitted connection, counted until it is dropped
+#[derive(Debug)]
+pub struct Ticket {
+    guard: Guard,
+    ip: IpAddr,
+}
+
+impl Ticket {
+
+    ///a bad head, a handshake which never finished or a denied target
+    pub fn failure(&self) {
+        self.guard.failure(self.ip);
+    }
+}
+
+impl Drop for Ticket {
+
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.guard.inner.state.lock() {
+            let left = match state.active.get_mut(&self.ip) {
+                Some(count) => {
+                    *count -= 1;
+                    *count
+                },
+                None => return,
+            };
+            if left == 0 {
+                let _ = state.active.remove(&self.ip);
+            }
+        }
+    }
+}
+
+impl Guard {
+
+    pub fn new() -> Self {
+        let inner = Inner {
+            cfg: RwLock::new(Default::default()),
+            state: Mutex::new(Default::default()),
+            held: Mutex::new(Default::default()),
+            wake: Condvar::new(),
+            banned: AtomicU64::new(0),
+            limited: AtomicU64::new(0),
+            bans: AtomicU64::new(0),
+        };
+        Guard {
+            inner: Arc::new(inner),
+        }
+    }
+
+    pub fn set_config(&self, cfg:GuardConfig) {
+        if let Ok(mut current) = self.inner.cfg.write() {
+            *current = cfg;
+        }
+    }
+
+    ///none if the ip is banned or has too many connections
+    pub fn admit(&self, ip:IpAddr) -> Option<Ticket> {
+        let cfg = *self.inner.cfg.read().ok()?;
+        let mut state = self.inner.state.lock().ok()?;
+        if state.banned(ip, Instant::now()) {
+            self.inner.banned.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        let count = state.active.entry(ip).or_insert(0);
+        if cfg.max_connections_per_ip > 0 && *count >= cfg.max_connections_per_ip {
+            self.inner.limited.fetch_add(1, Ordering::Relaxed);
+            info!("{} has {} connections, refused", ip, count);
+            return None;
+        }
+        *count += 1;
+        Some(Ticket {
+            guard: self.clone(),
+            ip: ip,
+        })
+    }
+
+    ///keep a refused connection to the deadline without a reply, like a bad head
+    pub fn hold(&self, stream:TcpStream, deadline:Instant) {
+        let mut held = match self.inner.held.lock() {
+            Ok(held) => held,
+            Err(_) => return,
+        };
+        if held.streams.len() >= MAX_HELD {
+            return;
+        }
+        let _ = stream.set_nonblocking(true);
+        held.streams.push((stream, deadline));
+        self.inner.wake.notify_one();
+        if !held.running {
+            held.running = true;
+            let guard = self.clone();
+            let _ = thread::spawn(move || guard.release());
+        }
+    }
+
+    ///close the held connections at their deadlines, return with the last one
+    fn release(&self) {
+        let mut held = match self.inner.held.lock() {
+            Ok(held) => held,
+            Err(_) => return,
+        };
+        let mut buf = vec![0u8; 1024];
+        loop {
+            let now = Instant::now();
+            let (expired, kept):(Vec<(TcpStream, Instant)>, Vec<(TcpStream, Instant)>) = held.streams.drain(..).partition(|&(_, deadline)| deadline <= now);
+            held.streams = kept;
+            for (mut stream, _) in expired {
+                //what is left unread would close with a reset instead of a fin
+                while let Ok(size) = stream.read(&mut buf) {
+                    if size == 0 {
+                        break;
+                    }
+                }
+            }
+            let next = match held.streams.iter().map(|&(_, deadline)| deadline).min() {
+                Some(next) => next,
+                None => {
+                    held.running = false;
+                    return;
+                },
+            };
+            held = match self.inner.wake.wait_timeout(held, next - now) {
+                Ok((held, _)) => held,
+                Err(_) => return,
+            };
+        }
+    }
+
+    fn failure(&self, ip:IpAddr) {
+        let cfg = match self.inner.cfg.read() {
+            Ok(cfg) => *cfg,
+            Err(_) => return,
+        };
+        if cfg.max_failures == 0 {
+            return;
+        }
+        let mut state = match self.inner.state.lock() {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+        let now = Instant::now();
+        let find_time = Duration::from_secs(cfg.find_time);
+        let count = {
+            let failures = state.failures.entry(ip).or_insert_with(VecDeque::new);
+            while failures.front().map(|at| now.duration_since(*at) > find_time).unwrap_or(false) {
+                let _ = failures.pop_front();
+            }
+            failures.push_back(now);
+            failures.len() as u64
+        };
+        if count >= cfg.max_failures {
+            let _ = state.failures.remove(&ip);
+            let _ = state.bans.insert(ip, now + Duration::from_secs(cfg.ban_time));
+            self.inner.bans.fetch_add(1, Ordering::Relaxed);
+            warn!("{} is banned for {}s, {} failed handshakes", ip, cfg.ban_time, count);
+        }
+        //forget the ips which stopped failing
+        if state.failures.len() > 10000 {
+            state.failures.retain(|_, failures| failures.back().map(|at| now.duration_since(*at) <= find_time).unwrap_or(false));
+        }
+    }
+
+    ///the banned ips and the seconds left, the expired bans are removed
+    pub fn bans(&self) -> Vec<(IpAddr, u64)> {
+        let mut state = match self.inner.state.lock() {
+            Ok(state) => state,
+            Err(_) => return Vec::new(),
+        };
+        let now = Instant::now();
+        state.bans.retain(|_, until| *until > now);
+        let mut bans:Vec<(IpAddr, u64)> = state.bans.iter().map(|(ip, until)| {
+            let left = until.duration_since(now);
+            (*ip, left.as_secs() + if left.subsec_nanos() > 0 { 1 } else { 0 })
+        }).collect();
+        bans.sort();
+        bans
+    }
+
+    ///false if the ip is not banned
+    pub fn unban(&self, ip:IpAddr) -> bool {
+        match self.inner.state.lock() {
+            Ok(mut state) => {
+                let _ = state.failures.remove(&ip);
+                state.bans.remove(&ip).is_some()
+            },
+            Err(_) => false,
+        }
+    }
+
+    pub fn collect(&self, out:&mut String) {
+        let banned = self.bans().len();
+        metrics::family(out, "ss_banned_ips", "gauge", "client ips banned for failed handshakes", &[(String::new(), banned as u64)]);
+        metrics::family(out, "ss_bans_total", "counter", "bans since the start", &[(String::new(), self.inner.bans.load(Ordering::Relaxed))]);
+        metrics::family(out, "ss_refused_connections_total", "counter", "connections closed on accept, by the reason", &[
+            (metrics::label("reason", "banned"), self.inner.banned.load(Ordering::Relaxed)),
+            (metrics::label("reason", "max_connections_per_ip"), self.inner.limited.load(Ordering::Relaxed)),
+        ]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guard(max_connections_per_ip:u64, max_failures:u64) -> Guard {
+        let guard = Guard::new();
+        guard.set_config(GuardConfig {
+            max_connections_per_ip: max_connections_per_ip,
+            max_failures: max_failures,
+            find_time: 60,
+            ban_time: 600,
+        });
+        guard
+    }
+
+    fn ago(secs:u64) -> Instant {
+        Instant::now().checked_sub(Duration::from_secs(secs)).unwrap()
+    }
+
+    #[test]
+    fn connections_per_ip() {
+        let guard = guard(2, 0);
+        let ip:IpAddr = "10.0.0.1".parse().unwrap();
+        let first = guard.admit(ip).unwrap();
+        let second = guard.admit(ip).unwrap();
+        assert!(guard.admit(ip).is_none());
+        assert!(guard.admit("10.0.0.2".parse().unwrap()).is_some());
+        drop(first);
+        let third = guard.admit(ip).unwrap();
+        assert_eq!(guard.inner.state.lock().unwrap().active[&ip], 2);
+        drop(second);
+        drop(third);
+        assert!(guard.inner.state.lock().unwrap().active.is_empty());
+        assert_eq!(guard.inner.limited.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn failures_within_find_time() {
+        let guard = guard(0, 3);
+        let ip:IpAddr = "10.0.0.1".parse().unwrap();
+        let ticket = guard.admit(ip).unwrap();
+        //two failures out of the window are forgotten
+        let old:VecDeque<Instant> = vec![ago(120), ago(90)].into_iter().collect();
+        let _ = guard.inner.state.lock().unwrap().failures.insert(ip, old);
+        ticket.failure();
+        ticket.failure();
+        assert!(guard.bans().is_empty());
+        ticket.failure();
+        assert_eq!(guard.bans(), vec![(ip, 600)]);
+        assert!(guard.admit(ip).is_none());
+        assert_eq!(guard.inner.banned.load(Ordering::Relaxed), 1);
+        assert_eq!(guard.inner.bans.load(Ordering::Relaxed), 1);
+        //the banned ip keeps its open connections
+        drop(ticket);
+        assert!(guard.admit("10.0.0.2".parse().unwrap()).is_some());
+    }
+
+    #[test]
+    fn no_bans_without_max_failures() {
+        let guard = guard(0, 0);
+        let ip:IpAddr = "10.0.0.1".parse().unwrap();
+        let ticket = guard.admit(ip).unwrap();
+        for _ in 0..100 {
+            ticket.failure();
+        }
+        assert!(guard.bans().is_empty());
+        assert!(guard.admit(ip).is_some());
+    }
+
+    #[test]
+    fn bans_expire() {
+        let guard = guard(0, 1);
+        let ip:IpAddr = "10.0.0.1".parse().unwrap();
+        let _ = guard.inner.state.lock().unwrap().bans.insert(ip, ago(1));
+        assert!(guard.bans().is_empty());
+        let _ = guard.inner.state.lock().unwrap().bans.insert(ip, ago(1));
+        assert!(guard.admit(ip).is_some());
+        assert!(guard.inner.state.lock().unwrap().bans.is_empty());
+    }
+
+    #[test]
+    fn unban() {
+        let guard = guard(0, 1);
+        let ip:IpAddr = "10.0.0.1".parse().unwrap();
+        guard.admit(ip).unwrap().failure();
+        assert!(guard.admit(ip).is_none());
+        assert!(guard.unban(ip));
+        assert!(!guard.unban(ip));
+        assert!(guard.admit(ip).is_some());
+        assert!(!guard.unban("10.0.0.2".parse().unwrap()));
+    }
+
+    #[test]
+    fn held_until_the_deadline() {
+        use std::io::Write;
+        use std::net::TcpListener;
+        let guard = guard(0, 0);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut clients = Vec::new();
+        for wait in [300u64, 100].iter() {
+            let mut client = TcpStream::connect(addr).unwrap();
+            client.write_all(b"\x00\x01\x02").unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            guard.hold(stream, Instant::now() + Duration::from_millis(*wait));
+            clients.push((client, Instant::now(), *wait));
+        }
+        for (mut client, start, wait) in clients {
+            let mut buf = [0u8; 8];
+            //closed with a fin, the unread bytes would reset it
+            assert_eq!(client.read(&mut buf).unwrap(), 0);
+            assert!(start.elapsed() >= Duration::from_millis(wait - 10));
+        }
+        assert!(guard.inner.held.lock().unwrap().streams.is_empty());
+    }
+}
@@ -0,0 +1,325 @@
+//! the manager protocol of shadowsocks-libev, one datagram per command over
+//! udp or a unix socket:
+//!
+//!   add: {"server_port": 8001, "password": "secret"}    -> ok
+//!   remove: {"server_port": 8001}                       -> ok
+//!   list                                                -> [{"server_port": "8001", "password": "secret"}]
+//!   ping                                                -> stat: {"8001": 11370}
+//!
+//! the ports added at runtime are kept over the reloads, the stat counts the
+//! bytes of every port in both directions.
+//!
+//! the tunnel has no authentication, the password is only kept for list and a
+//! new port is open to anyone who can reach it, add logs a warning about it.
+//! the methods are the ones the tunnel implements: none and plain.
+
+use define::ErrCode;
+use define::ErrCode::*;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::fs::FileTypeExt;
+use std::net::{TcpListener, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+
+use serde_json;
+use serde_json::Value;
+
+use config::{ServerConfig, METHODS};
+use metrics::Metrics;
+use signal;
+
+///applied by the accept loop of the server
+pub enum Change {
+    Add(ServerConfig, TcpListener),
+    Remove(u32),
+}
+
+struct Inner {
+    changes: Mutex<Vec<Change>>,
+    managed: Mutex<BTreeMap<u32, ServerConfig>>,
+    listening: RwLock<Vec<(String, u32, String)>>, //ip, port and the user of every listener
+    metrics: Metrics,
+}
+
+#[derive(Clone)]
+pub struct Manager {
+    inner: Arc<Inner>,
+}
+
+impl Manager {
+
+    pub fn new(metrics:Metrics) -> Self {
+        let inner = Inner {
+            changes: Mutex::new(Vec::new()),
+            managed: Mutex::new(BTreeMap::new()),
+            listening: RwLock::new(Vec::new()),
+            metrics: metrics,
+        };
+        Manager {
+            inner: Arc::new(inner),
+        }
+    }
+
+    ///the changes since the last call
+    pub fn take_changes(&self) -> Vec<Change> {
+        self.inner.changes.lock().map(|mut changes| changes.drain(..).collect()).unwrap_or_default()
+    }
+
+    ///the listeners after a change, for the stat and the address of the new ports
+    pub fn set_listening(&self, listening:Vec<(String, u32, String)>) {
+        if let Ok(mut current) = self.inner.listening.write() {
+            *current = listening;
+        }
+    }
+
+    ///a path is a unix socket, otherwise ip:port of udp
+    pub fn serve(&self, address:&str) -> Result<(), ErrCode> {
+        let manager = self.clone();
+        if address.contains('/') {
+            //the socket of an earlier run, any other file is kept
+            match fs::symlink_metadata(address) {
+                Ok(ref meta) if meta.file_type().is_socket() => {
+                    let _ = fs::remove_file(address);
+                },
+                Ok(_) => {
+                    error!("manager address {} exists and is not a unix socket", address);
+                    return Err(UrlErr);
+                },
+                Err(ref e) if e.kind() == ErrorKind::NotFound => {},
+                Err(e) => {
+                    error!("manager address {}, {}", address, e);
+                    return Err(UrlErr);
+                },
+            }
+            let socket = UnixDatagram::bind(address).or_else(|e| {
+                error!("{}", e);
+                Err(UrlErr)
+            })?;
+            info!("manager start listening on {}", address);
+            let _ = thread::spawn(move || {
+                let mut buf = vec![0u8; 4096];
+                loop {
+                    let (size, peer) = match socket.recv_from(&mut buf) {
+                        Ok(rst) => rst,
+                        Err(e) => {
+                            error!("{}", e);
+                            continue;
+                        },
+                    };
+                    let back = manager.handle(&String::from_utf8_lossy(&buf[..size]));
+                    //an unbound client can not get the reply
+                    if let Some(path) = peer.as_pathname() {
+                        let _ = socket.send_to(back.as_bytes(), path);
+                    }
+                }
+            });
+        } else {
+            let socket = UdpSocket::bind(address).or_else(|e| {
+                error!("{}", e);
+                Err(UrlErr)
+            })?;
+            info!("manager start listening on {}", address);
+            let _ = thread::spawn(move || {
+                let mut buf = vec![0u8; 4096];
+                loop {
+                    let (size, peer) = match socket.recv_from(&mut buf) {
+                        Ok(rst) => rst,
+                        Err(e) => {
+                            error!("{}", e);
+                            continue;
+                        },
+                    };
+                    let back = manager.handle(&String::from_utf8_lossy(&buf[..size]));
+                    let _ = socket.send_to(back.as_bytes(), peer);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn handle(&self, line:&str) -> String {
+        let line = line.trim_matches(|c:char| c.is_whitespace() || c == '\0');
+        let mut parts = line.splitn(2, ':');
+        let command = parts.next().unwrap_or("").trim();
+        let args = parts.next().unwrap_or("").trim();
+        info!("manager command: {}", line);
+        let rst = match command {
+            "add" => self.add(args),
+            "remove" => self.remove(args),
+            "list" => self.list(),
+            "ping" => self.ping(),
+            _ => Err(format!("unknown command {}", command)),
+        };
+        rst.unwrap_or_else(|e| {
+            warn!("manager {} failed, {}", command, e);
+            "err".to_string()
+        })
+    }
+
+    ///bound here, so a port in use is an error to the client
+    fn add(&self, args:&str) -> Result<String, String> {
+        let value:Value = serde_json::from_str(args).map_err(|e| e.to_string())?;
+        let port = server_port(&value)?;
+        let password = value["password"].as_str().ok_or("password must be a string")?.to_string();
+        let (ip, method) = {
+            let listening = self.inner.listening.read().map_err(|_| "lock")?;
+            if listening.iter().any(|&(_, p, _)| p == port) {
+                return Err(format!("port {} is listening", port));
+            }
+            let ip = listening.first().map(|l| l.0.clone()).unwrap_or("0.0.0.0".to_string());
+            (ip, value["method"].as_str().unwrap_or("none").to_string())
+        };
+        if !METHODS.contains(&method.as_str()) {
+            return Err(format!("method {} is not supported, only none and plain", method));
+        }
+        let listener = TcpListener::bind(format!("{}:{}", ip, port)).map_err(|e| e.to_string())?;
+        let _ = listener.set_nonblocking(true).map_err(|e| e.to_string())?;
+        let cfg = ServerConfig {
+            server: ip,
+            server_port: port,
+            password: password,
+            method: method,
+            plugin: None,
+            plugin_opts: None,
+            remarks: None,
+            acl: None,
+        };
+        let _ = self.inner.managed.lock().map_err(|_| "lock")?.insert(port, cfg.clone());
+        self.inner.changes.lock().map_err(|_| "lock")?.push(Change::Add(cfg, listener));
+        signal::wake();
+        //the clients of libev expect exactly ok
+        warn!("port {} is added by the manager, the password is not enforced", port);
+        Ok("ok".to_string())
+    }
+
+    ///only the ports added by the manager, the ones of the config stay
+    fn remove(&self, args:&str) -> Result<String, String> {
+        let value:Value = serde_json::from_str(args).map_err(|e| e.to_string())?;
+        let port = server_port(&value)?;
+        if self.inner.managed.lock().map_err(|_| "lock")?.remove(&port).is_none() {
+            return Err(format!("port {} is not managed", port));
+        }
+        self.inner.changes.lock().map_err(|_| "lock")?.push(Change::Remove(port));
+        signal::wake();
+        Ok("ok".to_string())
+    }
+
+    fn list(&self) -> Result<String, String> {
+        let managed = self.inner.managed.lock().map_err(|_| "lock")?;
+        let list:Vec<Value> = managed.values().map(|cfg| {
+            let mut item = serde_json::Map::new();
+            let _ = item.insert("server_port".to_string(), Value::String(cfg.server_port.to_string()));
+            let _ = item.insert("password".to_string(), Value::String(cfg.password.clone()));
+            Value::Object(item)
+        }).collect();
+        Ok(Value::Array(list).to_string())
+    }
+
+    fn ping(&self) -> Result<String, String> {
+        let listening = self.inner.listening.read().map_err(|_| "lock")?;
+        let mut stat = serde_json::Map::new();
+        for &(_, port, ref user) in listening.iter() {
+            let (up, down) = self.inner.metrics.user_bytes(user);
+            let _ = stat.insert(port.to_string(), Value::from(up + down));
+        }
+        //added, but not listened by the accept loop yet
+        for cfg in self.inner.managed.lock().map_err(|_| "lock")?.values() {
+            let _ = stat.entry(cfg.server_port.to_string()).or_insert(Value::from(0));
+        }
+        Ok(format!("stat: {}", Value::Object(stat)))
+    }
+}
+
+///a number or a string like libev
+fn server_port(value:&Value) -> Result<u32, String> {
+    let port = match value["server_port"] {
+        Value::String(ref s) => s.parse::<u64>().ok(),
+        ref v => v.as_u64(),
+    };
+    match port {
+        Some(port) if port > 0 && port <= 65535 => Ok(port as u32),
+        _ => Err("server_port must be a port between 1 and 65535".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///a port nothing listens on
+    fn free_port() -> u32 {
+        TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port() as u32
+    }
+
+    fn manager() -> (Manager, Metrics) {
+        let metrics = Metrics::new();
+        let manager = Manager::new(metrics.clone());
+        manager.set_listening(vec![("127.0.0.1".to_string(), 8388, "alice".to_string())]);
+        (manager, metrics)
+    }
+
+    #[test]
+    fn server_port_number_or_string() {
+        let port = |json:&str| server_port(&serde_json::from_str(json).unwrap());
+        assert_eq!(port(r#"{"server_port": 8001}"#), Ok(8001));
+        assert_eq!(port(r#"{"server_port": "8001"}"#), Ok(8001));
+        for json in [r#"{"server_port": 0}"#, r#"{"server_port": 65536}"#, r#"{"server_port": "80a"}"#, r#"{"server_port": -1}"#, r#"{}"#].iter() {
+            assert!(port(json).is_err(), "{}", json);
+        }
+    }
+
+    #[test]
+    fn add_list_ping_remove() {
+        let (manager, metrics) = manager();
+        let port = free_port();
+        let add = format!(r#"add: {{"server_port": {}, "password": "secret"}}"#, port);
+        assert_eq!(manager.handle(&add), "ok");
+        match manager.take_changes().pop() {
+            Some(Change::Add(cfg, listener)) => {
+                assert_eq!((cfg.server.as_str(), cfg.server_port, cfg.method.as_str()), ("127.0.0.1", port, "none"));
+                assert_eq!(listener.local_addr().unwrap().port() as u32, port);
+            },
+            _ => panic!("no port added"),
+        }
+        assert_eq!(manager.handle("list"), format!(r#"[{{"password":"secret","server_port":"{}"}}]"#, port));
+
+        let conn = metrics.open(Some("alice"));
+        conn.meter().up(100);
+        conn.meter().down(1000);
+        assert_eq!(manager.handle("ping"), format!(r#"stat: {{"{}":0,"8388":1100}}"#, port));
+
+        assert_eq!(manager.handle(&format!(r#"remove: {{"server_port": "{}"}}"#, port)), "ok");
+        match manager.take_changes().pop() {
+            Some(Change::Remove(removed)) => assert_eq!(removed, port),
+            _ => panic!("no port removed"),
+        }
+        assert_eq!(manager.handle("list"), "[]");
+        assert_eq!(manager.handle("ping"), r#"stat: {"8388":1100}"#);
+    }
+
+    #[test]
+    fn invalid_commands() {
+        let (manager, _) = manager();
+        let port = free_port();
+        for command in [
+            "add: {\"server_port\": 8388, \"password\": \"secret\"}".to_string(),
+            format!("add: {{\"server_port\": {}, \"password\": \"secret\", \"method\": \"aes-256-gcm\"}}", port),
+            format!("add: {{\"server_port\": {}}}", port),
+            "add: {server_port: 1}".to_string(),
+            format!("remove: {{\"server_port\": {}}}", port),
+            "remove: {\"server_port\": 8388}".to_string(),
+            "stop".to_string(),
+            String::new(),
+        ].iter() {
+            assert_eq!(manager.handle(command), "err", "{}", command);
+        }
+        assert!(manager.take_changes().is_empty());
+        //the datagrams of some clients end with a nul
+        assert_eq!(manager.handle(" ping\0"), r#"stat: {"8388":0}"#);
+        assert_eq!(manager.handle(&format!("add:{{\"server_port\":{},\"password\":\"\",\"method\":\"plain\"}}\n", port)), "ok");
+    }
+}
@@ -2,57 +2,279 @@ use define::ErrCode;
 use define::ErrCode::*;
 
 use std::thread;
+use std::io::ErrorKind;
+use std::time::Duration;
 use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+
+use config::{ServerConfig, LimitConfig, GuardConfig};
+use helper::Tracker;
+use metrics::Metrics;
+use access::AccessLog;
+use limit::Limiter;
+use signal;
+use signal::Event;
 
 mod protocol;
 use self::protocol::Protocol;
 
-mod cache;
-use self::cache::DnsCache;
+pub mod cache;
+pub use self::cache::DnsCache;
 
-pub struct Server {
+mod resolver;
+
+pub mod acl;
+pub use self::acl::Acl;
+
+pub mod guard;
+pub use self::guard::Guard;
+
+pub mod manager;
+pub use self::manager::Manager;
+use self::manager::Change;
+
+///one listening address, a user in the shadowsocks sense
+struct Listener {
     ip: String,
     port: u32,
+    user: String, //the remarks or the address, labels the metrics
     listener: TcpListener,
+    acl: Option<Arc<Acl>>, //checked after the global acl
+    managed: bool, //added by the manager, kept over the reloads
+}
+
+pub struct Server {
+    listeners: Vec<Listener>,
     time_out: u64,
     cache: DnsCache,
+    acl: Arc<Acl>,
+    connect_status: bool,
+    drain_time_out: u64,
+    tracker: Tracker,
+    metrics: Metrics,
+    manager: Manager,
+    access_log: AccessLog,
+    limiter: Limiter,
+    guard: Guard,
 }
 
 impl Server {
 
-    pub fn new(ip:&str, port:u32, time_out:u64) -> Result<Self, ErrCode> {
-        let url = format!("{}:{}", ip, port);
-        let listener = TcpListener::bind(&url).or_else(|e|{
-            error!("{}", e);
-            Err(UrlErr)
-        })?;
+    pub fn new(servers:&[ServerConfig], time_out:u64) -> Result<Self, ErrCode> {
         let cache = DnsCache::new();
-        Ok(Server {
-            ip: ip.to_string(),
-            port: port,
-            listener: listener,
+        let metrics = Metrics::new();
+        let mut server = Server {
+            listeners: Vec::new(),
             time_out: time_out,
             cache: cache,
-        })
+            acl: Arc::new(Acl::new("acl", Default::default())),
+            connect_status: false,
+            drain_time_out: 30,
+            tracker: Tracker::new(),
+            metrics: metrics.clone(),
+            manager: Manager::new(metrics),
+            access_log: AccessLog::new(),
+            limiter: Limiter::new(),
+            guard: Guard::new(),
+        };
+        let _ = server.set_listeners(servers)?;
+        Ok(server)
+    }
+
+    ///report the result of dialing the target to the local before tunneling
+    pub fn set_connect_status(&mut self, connect_status:bool) {
+        self.connect_status = connect_status;
+    }
+
+    ///listen on every server, an unchanged address keeps its socket.
+    ///nothing changes if one of the new addresses can not be bound.
+    ///the ports of the manager are not touched
+    pub fn set_listeners(&mut self, servers:&[ServerConfig]) -> Result<(), ErrCode> {
+        let mut bound = Vec::new();
+        for cfg in servers.iter() {
+            let exists = self.listeners.iter().any(|l| !l.managed && l.ip == cfg.server && l.port == cfg.server_port);
+            if exists {
+                bound.push(None);
+                continue;
+            }
+            let url = format!("{}:{}", cfg.server, cfg.server_port);
+            let listener = TcpListener::bind(&url).or_else(|e|{
+                error!("{}", e);
+                Err(UrlErr)
+            })?;
+            //accepted after signal::wait, a connection reset in between does not block the loop
+            let _ = listener.set_nonblocking(true).or(Err(SocketErr))?;
+            bound.push(Some(listener));
+        }
+        let (managed, mut old):(Vec<Listener>, Vec<Listener>) = self.listeners.drain(..).partition(|l| l.managed);
+        for (cfg, listener) in servers.iter().zip(bound.into_iter()) {
+            let user = match cfg.remarks {
+                Some(ref remarks) => remarks.clone(),
+                None => format!("{}:{}", cfg.server, cfg.server_port),
+            };
+            let acl = cfg.acl.clone().map(|acl| Arc::new(Acl::new(&format!("servers[{}].acl", user), acl)));
+            let listener = match listener {
+                Some(listener) => {
+                    info!("server listen on {}:{}", cfg.server, cfg.server_port);
+                    listener
+                },
+                None => {
+                    let pos = old.iter().position(|l| l.ip == cfg.server && l.port == cfg.server_port);
+                    match pos {
+                        Some(pos) => old.remove(pos).listener,
+                        None => continue, //the same address twice
+                    }
+                },
+            };
+            self.listeners.push(Listener {
+                ip: cfg.server.clone(),
+                port: cfg.server_port,
+                user: user,
+                listener: listener,
+                acl: acl,
+                managed: false,
+            });
+        }
+        for listener in old {
+            info!("server stop listening on {}:{}", listener.ip, listener.port);
+        }
+        self.listeners.extend(managed);
+        self.publish();
+        Ok(())
     }
 
-    //开启监听
-    pub fn start(&mut self) {
-        info!("local server start listening on {}:{}", self.ip, self.port);
-        for stream_rst in self.listener.incoming() {
-            let time_out = self.time_out;
-            let cache = self.cache.clone();
-            if let Ok(stream) = stream_rst {
-                let _ = Self::handle_stream(stream, time_out, cache);
+    ///the ports added and removed by the manager since the last call
+    fn apply_changes(&mut self) {
+        let changes = self.manager.take_changes();
+        if changes.is_empty() {
+            return;
+        }
+        for change in changes {
+            match change {
+                Change::Add(cfg, listener) => {
+                    info!("server listen on {}:{}, added by the manager", cfg.server, cfg.server_port);
+                    self.listeners.push(Listener {
+                        ip: cfg.server.clone(),
+                        port: cfg.server_port,
+                        user: format!("{}:{}", cfg.server, cfg.server_port),
+                        listener: listener,
+                        acl: None,
+                        managed: true,
+                    });
+                },
+                Change::Remove(port) => {
+                    info!("server stop listening on port {}, removed by the manager", port);
+                    self.listeners.retain(|l| !(l.managed && l.port == port));
+                },
+            }
+        }
+        self.publish();
+    }
+
+    fn publish(&self) {
+        let listening = self.listeners.iter().map(|l| (l.ip.clone(), l.port, l.user.clone())).collect();
+        self.manager.set_listening(listening);
+    }
+
+    pub fn cache(&self) -> DnsCache {
+        self.cache.clone()
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.clone()
+    }
+
+    pub fn manager(&self) -> Manager {
+        self.manager.clone()
+    }
+
+    ///append a json line for every session to the file, reopened on every call
+    pub fn set_access_log(&mut self, path:Option<&str>) -> Result<(), ErrCode> {
+        self.access_log.set_path(path)
+    }
+
+    ///the bandwidth of the new tunnels, the user is the listener
+    pub fn set_limits(&mut self, limits:LimitConfig) {
+        self.limiter.set_config(limits);
+    }
+
+    ///the connections per client ip and the bans
+    pub fn set_guard(&mut self, guard:GuardConfig) {
+        self.guard.set_config(guard);
+    }
+
+    pub fn guard(&self) -> Guard {
+        self.guard.clone()
+    }
+
+    ///the targets the new connections may reach
+    pub fn set_acl(&mut self, acl:Acl) {
+        self.acl = Arc::new(acl);
+    }
+
+    pub fn set_time_out(&mut self, time_out:u64) {
+        self.time_out = time_out;
+    }
+
+    ///seconds to wait for the active tunnels on shutdown
+    pub fn set_drain_time_out(&mut self, drain_time_out:u64) {
+        self.drain_time_out = drain_time_out;
+    }
+
+    //开启监听, return after SIGTERM/SIGINT and the tunnels are drained, or on SIGHUP
+    pub fn start(&mut self) -> Event {
+        for listener in self.listeners.iter() {
+            info!("server start listening on {}:{}", listener.ip, listener.port);
+        }
+        while !signal::is_terminated() {
+            if signal::take_reload() {
+                return Event::Reload;
+            }
+            self.apply_changes();
+            let fds:Vec<_> = self.listeners.iter().map(|l| l.listener.as_raw_fd()).collect();
+            let ready = signal::wait(&fds);
+            for listener in self.listeners.iter().filter(|l| ready.contains(&l.listener.as_raw_fd())) {
+                match listener.listener.accept() {
+                    Ok((stream, _)) => {
+                        let _ = self.handle_stream(stream, listener);
+                    },
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => {},
+                    Err(e) => {
+                        error!("{}", e);
+                    },
+                }
             }
         }
+        info!("server stop accepting, {} tunnels active", self.tracker.len());
+        let count = self.tracker.drain(Duration::from_secs(self.drain_time_out));
+        info!("server stopped, {} tunnels force-closed", count);
+        Event::Terminate
     }
 
-    pub fn handle_stream(stream:TcpStream, time_out:u64, cache:DnsCache) -> Result<(), ErrCode> {
+    ///serve the stream in its own thread with the current settings
+    fn handle_stream(&self, stream:TcpStream, listener:&Listener) -> Result<(), ErrCode> {
         let peer_addr = stream.peer_addr().or(Err(SocketErr))?;
         info!("{}", peer_addr);
+        let ticket = match self.guard.admit(peer_addr.ip()) {
+            Some(ticket) => ticket,
+            None => {
+                //an instant close would tell the prober about the ban
+                self.guard.hold(stream, protocol::handshake_deadline(self.time_out));
+                return Err(NetErr);
+            },
+        };
+        let _ = stream.set_nonblocking(false).or(Err(SocketErr))?;
+        let guard = self.tracker.register(&stream)?;
+        let mut acls = vec![self.acl.clone()];
+        acls.extend(listener.acl.clone());
+        let conn = self.metrics.open(Some(&listener.user));
+        let session = self.access_log.open(Some(peer_addr), Some(&listener.user));
+        let mut pro = Protocol::new(stream, self.time_out, self.cache.clone(), acls, self.connect_status, conn, session);
+        pro.set_throttle(self.limiter.open(Some(&listener.user)));
+        pro.set_ticket(ticket);
         let _ = thread::spawn(move|| {
-            let mut pro = Protocol::new(stream, time_out, cache);
+            let _guard = guard;
             let _ = pro.start();
         });
         Ok(())
@@ -1,11 +1,10 @@
-use define::{Ip, ErrCode};
+use define::{Ip, ErrCode, Reply};
 use define::ErrCode::*;
 
-use std::net::{Shutdown, TcpStream, Ipv4Addr};
-use std::net::ToSocketAddrs;
+use std::net::{Shutdown, TcpStream, Ipv4Addr, Ipv6Addr, IpAddr};
 use std::io::{Read, Write};
 use std::io::Cursor;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::collections::HashSet;
 use std::{thread};
 
@@ -15,16 +14,25 @@ use byteorder::{BigEndian, ReadBytesExt};
 extern crate bytes;
 use bytes::{BytesMut, BufMut};
 
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{channel};
 
 use helper;
 use helper::encode;
+use server::acl::Acl;
 use server::cache::DnsCache;
+use metrics::Connection;
+use access;
+use access::Session;
+use limit::Throttle;
+use server::guard::Ticket;
 
 #[derive(Default, Debug)]
 struct ConnectHead {
     atyp: u8,
     ip: Ip,
+    ipv6: Option<Ipv6Addr>,
     url: String,
     port: u16,
 }
@@ -46,6 +54,26 @@ impl ConnectHead {
     pub fn set_url(&mut self, url:&str) {
         self.url = url.to_string();
     }
+
+    pub fn set_ipv6(&mut self, ipv6:Ipv6Addr) {
+        self.ipv6 = Some(ipv6);
+    }
+}
+
+///milliseconds a client has for the head, the timeout but at most a minute.
+///the deadline is random between a sixth of it and it for every connection,
+///a bad head is read to the same deadline, so the close tells nothing about it
+const HANDSHAKE_MAX:u64 = 60*1000;
+///connections read to the deadline at a time, the others keep the socket unread until it
+const MAX_SINKS:usize = 256;
+
+static SINKS:AtomicUsize = AtomicUsize::new(0);
+
+///the random deadline of a new head
+pub fn handshake_deadline(time_out:u64) -> Instant {
+    let max = time_out.saturating_mul(1000).min(HANDSHAKE_MAX).max(6);
+    let wait = max / 6 + helper::rand_u64() % (max - max / 6);
+    Instant::now() + Duration::from_millis(wait)
 }
 
 #[derive(PartialEq, Eq, Debug, Copy, Clone)]
@@ -72,12 +100,18 @@ pub struct Protocol {
     target_stream: Option<TcpStream>, //stream to the target
     time_out: u64,
     cache: DnsCache,
+    acls: Vec<Arc<Acl>>, //the global acl, then the one of the listener
+    connect_status: bool, //send the status frame after dialing the target
+    conn: Connection,
+    session: Session,
+    throttle: Throttle,
+    ticket: Option<Ticket>,
+    deadline: Instant, //of the head
 }
 
 impl Protocol {
     
-    pub fn new(stream:TcpStream, time_out:u64, cache:DnsCache) -> Self {
-        let _ = stream.set_read_timeout(Some(Duration::from_millis(time_out)));
+    pub fn new(stream:TcpStream, time_out:u64, cache:DnsCache, acls:Vec<Arc<Acl>>, connect_status:bool, conn:Connection, session:Session) -> Self {
         Protocol {
             stream: stream,
             buf: BytesMut::with_capacity(1024),
@@ -86,12 +120,43 @@ impl Protocol {
             target_stream: None,
             time_out: time_out,
             cache: cache,
+            acls: acls,
+            connect_status: connect_status,
+            conn: conn,
+            session: session,
+            throttle: Default::default(),
+            ticket: None,
+            deadline: handshake_deadline(time_out),
         }
     }
 
+    ///the bandwidth limits of the tunnel
+    pub fn set_throttle(&mut self, throttle:Throttle) {
+        self.throttle = throttle;
+    }
+
+    ///the connection slot of the client ip, told about the failed handshakes
+    pub fn set_ticket(&mut self, ticket:Ticket) {
+        self.ticket = Some(ticket);
+    }
+
+    ///serve the stream, then write the access log
     pub fn start(&mut self) -> Result<(), ErrCode> {
+        let rst = self.serve();
+        let (up, down) = self.conn.bytes();
+        self.session.finish(up, down);
+        rst
+    }
+
+    fn serve(&mut self) -> Result<(), ErrCode> {
         let mut buf = vec![0u8; 1024];
+        let mut received = false;
         loop {
+            //the tunnel returns from handle, so every read here is of the head
+            let now = Instant::now();
+            if now >= self.deadline || self.stream.set_read_timeout(Some(self.deadline - now)).is_err() {
+                break;
+            }
             let rst = self.stream.read(&mut buf);
             match rst {
                 Ok(size) => {
@@ -99,6 +164,7 @@ impl Protocol {
                     if size == 0 {
                         break;
                     }
+                    received = true;
                     self.buf.reserve(size);
                     self.buf.extend_from_slice(&encode(&buf[0..size]));
                     let _ = self.handle()?;
@@ -109,21 +175,90 @@ impl Protocol {
                 }
             }
         }
+        //closed or timed out before the head was complete
+        if self.step == ProStep::Connect {
+            self.handshake_failure(SocketErr);
+            //a connection without data is no failure, like the checks of a load balancer
+            if received {
+                self.failure();
+            }
+        }
         Ok(())
     }
 
+    ///read and drop until the client closes or the deadline of the head, never reply.
+    ///a prober sees the same close for a bad, an incomplete or a slow head
+    fn sink(&mut self) {
+        self.buf.clear();
+        let mut buf = vec![0u8; 1024];
+        if SINKS.fetch_add(1, Ordering::SeqCst) >= MAX_SINKS {
+            let _ = SINKS.fetch_sub(1, Ordering::SeqCst);
+            //too many sinks, hold the socket without reading
+            let now = Instant::now();
+            if now < self.deadline {
+                thread::sleep(self.deadline - now);
+            }
+            //what is left unread would close with a reset instead of a fin
+            if self.stream.set_nonblocking(true).is_ok() {
+                while let Ok(size) = self.stream.read(&mut buf) {
+                    if size == 0 {
+                        break;
+                    }
+                }
+            }
+            return;
+        }
+        loop {
+            let now = Instant::now();
+            if now >= self.deadline || self.stream.set_read_timeout(Some(self.deadline - now)).is_err() {
+                break;
+            }
+            match self.stream.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {},
+            }
+        }
+        let _ = SINKS.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    ///counts for the ban of the client ip
+    fn failure(&self) {
+        if let Some(ref ticket) = self.ticket {
+            ticket.failure();
+        }
+    }
+
+    fn handshake_failure(&mut self, e:ErrCode) {
+        self.conn.handshake_failure(e);
+        self.session.end("handshake_failed", Some(format!("{:?}", e)));
+    }
+
     pub fn handle(&mut self) -> Result<(), ErrCode> {
         match self.step {
             ProStep::Connect => {
-                let _ = self.connect()?;
+                if let Err(e) = self.connect() {
+                    self.handshake_failure(e);
+                    self.failure();
+                    self.sink();
+                    return Err(e);
+                }
+                //the connect head is not finished
+                if self.step != ProStep::ConnectTarget {
+                    return Ok(());
+                }
+                let start = Instant::now();
                 let rst = self.connect_target();
                 match rst {
                     Ok(_) => {
-                        //let _ = self.connect_success()?;
+                        self.conn.connected(start.elapsed());
+                        let _ = self.connect_success()?;
                         let _ = self.tunnel()?;
                     },
-                    Err(_e) => {
-                        let _ = self.connect_err()?;
+                    Err(rep) => {
+                        error!("connect {}:{} failed, {}.", self.conn_head.url, self.conn_head.port, rep.description());
+                        self.conn.connect_failure(rep);
+                        self.session.end("connect_failed", Some(format!("{:?}", rep)));
+                        let _ = self.connect_err(rep)?;
                     },
                 }
             },
@@ -160,6 +295,16 @@ impl Protocol {
                     let ip = Ip::new(fs, s, t, f);
                     head.set_ip(ip);
                 },
+                4 => {
+                    if self.buf.len() < 19 {
+                        return Ok(());
+                    }
+                    let _ = self.buf.split_to(1);
+                    let ip_buf = self.buf.split_to(16);
+                    let mut octets = [0u8; 16];
+                    octets.copy_from_slice(&ip_buf);
+                    head.set_ipv6(Ipv6Addr::from(octets));
+                },
                 3 => {
                     if self.buf.len() < 2 {
                         return Ok(());
@@ -183,32 +328,87 @@ impl Protocol {
         let port = cur.read_u16::<BigEndian>().or(Err(SocketErr))?;
         head.set_port(port);
         info!("{:?}", head);
+        let host = if head.atyp == 3 { Some(head.url.as_str()) } else { None };
+        let ip = match (head.atyp, head.ipv6) {
+            (1, _) => Some(IpAddr::V4(head.ip.to_ipv4())),
+            (4, Some(ipv6)) => Some(IpAddr::V6(ipv6)),
+            _ => None,
+        };
+        self.session.set_target(host, ip, port);
         self.conn_head = head;
+        self.step.next();
         Ok(())
     }
 
-    pub fn connect_target(&mut self) -> Result<(), ErrCode> {
-        let atyp = self.conn_head.atyp;
-        let ipv4_addr;
-        if atyp == 1 {
-            ipv4_addr = self.conn_head.ip.to_ipv4();
-        } else {
-            self.conn_head.ip = self.cache.get_ip(&self.conn_head.url)?;
-            ipv4_addr = self.conn_head.ip.to_ipv4();
-        }
-        info!("{}:{}:{} and buf len is {}.", self.conn_head.url, ipv4_addr, self.conn_head.port, self.buf.len());
+    pub fn connect_target(&mut self) -> Result<(), Reply> {
         let time_out = Duration::from_secs(self.time_out);
-        let addr = (ipv4_addr, self.conn_head.port).to_socket_addrs().or(Err(NetErr))?.next().ok_or(NetErr)?;
-        self.target_stream = Some(TcpStream::connect_timeout(&addr, time_out).or(Err(NetErr))?);
+        let _ = self.check_acl(None).map_err(|rep| {
+            self.session.set_decision("deny");
+            self.failure();
+            rep
+        })?;
+        let ips = match (self.conn_head.atyp, self.conn_head.ipv6) {
+            (1, _) => vec![IpAddr::V4(self.conn_head.ip.to_ipv4())],
+            (4, Some(ipv6)) => vec![IpAddr::V6(ipv6)],
+            //dns failure
+            _ => self.cache.get_ips(&self.conn_head.url).or(Err(Reply::HostUnreachable))?,
+        };
+        //skip the denied addresses, a domain may resolve to public and private ones
+        let mut denied = None;
+        let ips:Vec<IpAddr> = ips.into_iter().filter(|ip| {
+            match self.check_acl(Some(*ip)) {
+                Ok(_) => true,
+                Err(rep) => {
+                    denied = Some(rep);
+                    false
+                },
+            }
+        }).collect();
+        if ips.is_empty() {
+            if denied.is_some() {
+                self.session.set_decision("deny");
+                self.failure();
+            }
+            return Err(denied.unwrap_or(Reply::HostUnreachable));
+        }
+        self.session.set_decision("allow");
+        info!("{}:{} {:?} and buf len is {}.", self.conn_head.url, self.conn_head.port, ips, self.buf.len());
+        let target_stream = helper::happy_eyeballs(&ips, self.conn_head.port, time_out).map_err(|e| {
+            //the cached addresses may be stale, a failed lookup stays as a negative entry
+            if self.conn_head.atyp == 3 {
+                let _ = self.cache.remove(&self.conn_head.url);
+            }
+            Reply::from(&e)
+        })?;
+        if let Ok(addr) = target_stream.peer_addr() {
+            self.session.set_ip(addr.ip());
+        }
+        self.target_stream = Some(target_stream);
         Ok(())
     }
 
+    ///the ip is none before the resolution, a denied target is logged with the rule
+    fn check_acl(&self, ip:Option<IpAddr>) -> Result<(), Reply> {
+        let domain = if self.conn_head.atyp == 3 { Some(self.conn_head.url.as_str()) } else { None };
+        let rst = self.acls.iter().map(|acl| acl.check(domain, ip.as_ref(), self.conn_head.port)).find(|rst| rst.is_err());
+        rst.unwrap_or(Ok(())).map_err(|rule| {
+            let target = match (domain, ip) {
+                (Some(domain), _) => domain.to_string(),
+                (None, Some(ip)) => ip.to_string(),
+                (None, None) => String::new(),
+            };
+            warn!("denied {}:{} by {}", target, self.conn_head.port, rule);
+            Reply::NotAllowed
+        })
+    }
+
     pub fn tunnel(&mut self) -> Result<(), ErrCode> {
-        let (sx, rx) = channel::<u64>();
+        let (sx, rx) = channel::<&'static str>();
         let stream = self.stream.try_clone().or(Err(SocketErr))?;
         let mut target_stream = self.target_stream.take().ok_or(SocketErr)?;
         //write the self.buf first
         let _ = target_stream.write_all(&self.buf).or(Err(SocketErr))?;
+        self.conn.meter().up(self.buf.len());
 
         //write time out 1 minute
         let _ = stream.set_write_timeout(Some(Duration::from_millis(60*1000))).or(Err(SocketErr))?;
@@ -222,61 +422,70 @@ impl Protocol {
         let _ = target_stream.set_read_timeout(None).or(Err(SocketErr))?;
 
         let sx1 = sx.clone();
+        let meter1 = self.conn.meter();
+        let throttle1 = self.throttle.clone();
         let mut stream_read = stream.try_clone().or(Err(SocketErr))?;
         let mut target_stream_write = target_stream.try_clone().or(Err(SocketErr))?;
         let _th1 = thread::spawn(move || {
             let mut buf = vec![0u8; 1024];
-            loop {
+            let reason = loop {
                 let rst = stream_read.read(&mut buf);
                 match rst {
                     Ok(size) => {
                         //info!("local stream receive {} bytes data.", size);
                         if size == 0 {
-                            break;
+                            break "client_closed";
                         }
+                        throttle1.up(size);
                         let rst = target_stream_write.write_all(&encode(&buf[0..size]));
-                        if rst.is_err() {
-                            break;
+                        if let Err(e) = rst {
+                            break access::io_reason(&e, "target_error");
                         }
+                        meter1.up(size);
                     },
                     Err(e) => {
                         error!("{}", e);
-                        break;
+                        break access::io_reason(&e, "client_error");
                     }
                 }
-            }
-            let _ = sx1.send(0);
+            };
+            let _ = sx1.send(reason);
         });
 
         let sx2 = sx.clone();
+        let meter2 = self.conn.meter();
+        let throttle2 = self.throttle.clone();
         let mut stream_write = stream.try_clone().or(Err(SocketErr))?;
         let mut target_stream_read = target_stream.try_clone().or(Err(SocketErr))?;
         let _th2 = thread::spawn(move || {
             let mut buf = vec![0u8; 1024];
-            loop {
+            let reason = loop {
                 let rst = target_stream_read.read(&mut buf);
                 match rst {
                     Ok(size) => {
                         //info!("target stream receive {} bytes data.", size);
                         if size == 0 {
-                            break;
+                            break "target_closed";
                         }
+                        throttle2.down(size);
                         let rst = stream_write.write_all(&encode(&buf[0..size]));
-                        if rst.is_err() {
-                            break;
+                        if let Err(e) = rst {
+                            break access::io_reason(&e, "client_error");
                         }
+                        meter2.down(size);
                     },
                     Err(e) => {
                         error!("{}", e);
-                        break;
+                        break access::io_reason(&e, "target_error");
                     }
                 }
-            }
-            let _ = sx2.send(0);
+            };
+            let _ = sx2.send(reason);
         });
 
         //th1 or th2 finished, will return.
-        let _ = rx.recv().or(Err(SocketErr))?;
+        let reason = rx.recv().or(Err(SocketErr))?;
+        self.session.end(reason, None);
         let _ = stream.shutdown(Shutdown::Both);
         let _ = target_stream.shutdown(Shutdown::Both);
 
@@ -285,14 +494,20 @@ impl Protocol {
 
     ///connect the target success
     pub fn connect_success(&mut self) -> Result<(), ErrCode> {
-        Ok(())
+        self.write_status(Reply::Succeeded)
     }
     
-    ///can not connect the target, clear the site cache
-    pub fn connect_err(&mut self) -> Result<(), ErrCode> {
-        if self.conn_head.url.len() > 0 {
-            self.cache.remove(&self.conn_head.url)?;
+    ///can not connect the target
+    pub fn connect_err(&mut self, rep:Reply) -> Result<(), ErrCode> {
+        self.write_status(rep)
+    }
+
+    ///send the status frame to the local, one encoded byte holding the socks5 reply code
+    pub fn write_status(&mut self, rep:Reply) -> Result<(), ErrCode> {
+        if !self.connect_status {
+            return Ok(());
         }
+        let _ = self.stream.write_all(&encode(&[u8::from(rep)])).or(Err(SocketErr))?;
         Ok(())
     }
 
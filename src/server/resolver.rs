@@ -0,0 +1,509 @@
+//! dns client of ssserver, RFC 1035 over udp, tcp when the answer is truncated.
+//! the A and AAAA records are queried, the CNAME chains are followed.
+
+use define::ErrCode;
+use define::ErrCode::*;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use bytes::{BytesMut, BufMut};
+
+use helper;
+
+const TYPE_A:u16 = 1;
+const TYPE_CNAME:u16 = 5;
+const TYPE_AAAA:u16 = 28;
+const CLASS_IN:u16 = 1;
+const RCODE_NXDOMAIN:u16 = 3;
+const MAX_CNAME:usize = 8;
+
+#[derive(Debug, Default)]
+struct Answer {
+    addrs: HashMap<String, Vec<IpAddr>>, //by the owner name
+    cnames: HashMap<String, String>,
+    ttl: Option<u32>, //the smallest of the records
+}
+
+#[derive(Debug, Clone)]
+pub struct Resolver {
+    nameservers: Vec<SocketAddr>,
+    time_out: Duration,
+    retries: u64,
+}
+
+impl Resolver {
+
+    pub fn new(nameservers:Vec<SocketAddr>, time_out:Duration, retries:u64) -> Self {
+        Resolver {
+            nameservers: nameservers,
+            time_out: time_out,
+            retries: retries,
+        }
+    }
+
+    ///the addresses and the smallest ttl of the chain, AAAA and A are queried at once.
+    ///without the answer of one type the ttl is 0, so the cache keeps the other one
+    ///for min_ttl only. UrlErr if the name does not exist, NetErr if no nameserver
+    ///answered or the cname chain is too long
+    pub fn resolve(&self, name:&str) -> Result<(Vec<IpAddr>, Option<u32>), ErrCode> {
+        if let Ok(ip) = name.trim_matches(|c| c == '[' || c == ']').parse::<IpAddr>() {
+            return Ok((vec![ip], None));
+        }
+        let (sx, rx) = channel();
+        let resolver = self.clone();
+        let name_v6 = name.to_string();
+        let _ = thread::spawn(move || {
+            let _ = sx.send(resolver.resolve_type(&name_v6, TYPE_AAAA));
+        });
+        let a = self.resolve_type(name, TYPE_A);
+        //the answer is cached, without the AAAA records the name would be ipv4 only for the ttl
+        let aaaa = match a {
+            Ok(_) => rx.recv_timeout(self.time_out).unwrap_or(Err(NetErr)),
+            Err(_) => rx.recv().unwrap_or(Err(NetErr)),
+        };
+        let mut ips = Vec::new();
+        let mut ttl = None;
+        let mut err = None;
+        for rst in vec![aaaa, a] {
+            match rst {
+                Ok((found, found_ttl)) => {
+                    ips.extend(found);
+                    ttl = min_ttl(ttl, found_ttl);
+                },
+                //the name does not exist
+                Err(UrlErr) => err = Some(UrlErr),
+                Err(e) => err = err.or(Some(e)),
+            }
+        }
+        match err {
+            _ if ips.is_empty() => Err(err.unwrap_or(UrlErr)),
+            Some(_) => Ok((ips, Some(0))),
+            None => Ok((ips, ttl)),
+        }
+    }
+
+    ///follow the cnames, a chain which ends without the records is queried again
+    fn resolve_type(&self, name:&str, qtype:u16) -> Result<(Vec<IpAddr>, Option<u32>), ErrCode> {
+        let mut name = name.trim_end_matches('.').to_lowercase();
+        let mut ttl = None;
+        for _ in 0..MAX_CNAME {
+            let answer = self.query(&name, qtype)?;
+            ttl = min_ttl(ttl, answer.ttl);
+            let mut hops = 0;
+            while let Some(cname) = answer.cnames.get(&name) {
+                name = cname.clone();
+                hops += 1;
+                if hops > MAX_CNAME {
+                    return Err(NetErr);
+                }
+            }
+            if let Some(ips) = answer.addrs.get(&name) {
+                return Ok((ips.clone(), ttl));
+            }
+            if hops == 0 {
+                //no data of this type
+                return Ok((Vec::new(), ttl));
+            }
+        }
+        //the name exists, the other type may still answer
+        Err(NetErr)
+    }
+
+    ///every nameserver in turn, the rounds are repeated retries times
+    fn query(&self, name:&str, qtype:u16) -> Result<Answer, ErrCode> {
+        let mut last_err = NetErr;
+        for _ in 0..self.retries + 1 {
+            for server in self.nameservers.iter() {
+                let id = random_id();
+                let request = build_query(id, name, qtype)?;
+                let rst = self.query_udp(server, id, &request).and_then(|(response, truncated)| {
+                    if truncated {
+                        debug!("{} truncated the answer of {}, retry over tcp", server, name);
+                        self.query_tcp(server, id, &request)
+                    } else {
+                        Ok(response)
+                    }
+                });
+                match rst.and_then(|response| parse_response(&response, id, name, qtype)) {
+                    Ok(answer) => return Ok(answer),
+                    Err(UrlErr) => return Err(UrlErr),
+                    Err(e) => {
+                        warn!("nameserver {} failed for {}", server, name);
+                        last_err = e;
+                    },
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    ///the source port is an ephemeral one, picked at random by the system
+    fn query_udp(&self, server:&SocketAddr, id:u16, request:&[u8]) -> Result<(Vec<u8>, bool), ErrCode> {
+        let bind = if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(bind).or(Err(NetErr))?;
+        let _ = socket.set_read_timeout(Some(self.time_out));
+        let _ = socket.connect(server).or(Err(NetErr))?;
+        let _ = socket.send(request).or(Err(NetErr))?;
+        let mut buf = vec![0u8; 4096];
+        loop {
+            let size = socket.recv(&mut buf).or(Err(NetErr))?;
+            //a late answer of an earlier query, or a spoofed one
+            if size < 12 || BigEndian::read_u16(&buf[0..2]) != id || !same_question(&buf[..size], request) {
+                continue;
+            }
+            let truncated = buf[2] & 0x02 != 0;
+            return Ok((buf[..size].to_vec(), truncated));
+        }
+    }
+
+    fn query_tcp(&self, server:&SocketAddr, id:u16, request:&[u8]) -> Result<Vec<u8>, ErrCode> {
+        let mut stream = TcpStream::connect_timeout(server, self.time_out).or(Err(NetErr))?;
+        let _ = stream.set_read_timeout(Some(self.time_out));
+        let _ = stream.set_write_timeout(Some(self.time_out));
+        let mut buf = BytesMut::with_capacity(2 + request.len());
+        buf.put_u16_be(request.len() as u16);
+        buf.put_slice(request);
+        let _ = stream.write_all(&buf).or(Err(NetErr))?;
+        let mut len = [0u8; 2];
+        let _ = stream.read_exact(&mut len).or(Err(NetErr))?;
+        let mut response = vec![0u8; BigEndian::read_u16(&len) as usize];
+        let _ = stream.read_exact(&mut response).or(Err(NetErr))?;
+        if response.len() < 12 || BigEndian::read_u16(&response[0..2]) != id || !same_question(&response, request) {
+            return Err(NetErr);
+        }
+        Ok(response)
+    }
+}
+
+///the id of a query, from the system so an off-path attacker can not guess it
+fn random_id() -> u16 {
+    let mut buf = [0u8; 2];
+    match File::open("/dev/urandom").and_then(|mut file| file.read_exact(&mut buf)) {
+        Ok(_) => BigEndian::read_u16(&buf),
+        Err(_) => helper::rand_u64() as u16,
+    }
+}
+
+///the response has the one question of the request, byte for byte but the case
+fn same_question(response:&[u8], request:&[u8]) -> bool {
+    let question = &request[12..];
+    response.len() >= 12 + question.len()
+        && BigEndian::read_u16(&response[4..6]) == 1
+        && response[12..12 + question.len()].eq_ignore_ascii_case(question)
+}
+
+fn min_ttl(a:Option<u32>, b:Option<u32>) -> Option<u32> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+///recursion desired, one question
+fn build_query(id:u16, name:&str, qtype:u16) -> Result<Vec<u8>, ErrCode> {
+    let mut buf = BytesMut::with_capacity(18 + name.len());
+    buf.put_u16_be(id);
+    buf.put_u16_be(0x0100);
+    buf.put_u16_be(1);
+    buf.put_u16_be(0);
+    buf.put_u16_be(0);
+    buf.put_u16_be(0);
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(UrlErr);
+        }
+        buf.put_u8(label.len() as u8);
+        buf.put_slice(label.as_bytes());
+    }
+    buf.put_u8(0);
+    buf.put_u16_be(qtype);
+    buf.put_u16_be(CLASS_IN);
+    Ok(buf.to_vec())
+}
+
+///UrlErr for NXDOMAIN, NetErr for the other failures so the next nameserver is asked.
+///the question must be the name and the type which were queried
+fn parse_response(buf:&[u8], id:u16, name:&str, qtype:u16) -> Result<Answer, ErrCode> {
+    if buf.len() < 12 || BigEndian::read_u16(&buf[0..2]) != id || buf[2] & 0x80 == 0 {
+        return Err(NetErr);
+    }
+    if BigEndian::read_u16(&buf[4..6]) != 1 {
+        return Err(NetErr);
+    }
+    let (qname, next) = read_name(buf, 12)?;
+    if next + 4 > buf.len() || qname != name.trim_end_matches('.').to_lowercase()
+        || BigEndian::read_u16(&buf[next..]) != qtype || BigEndian::read_u16(&buf[next + 2..]) != CLASS_IN {
+        return Err(NetErr);
+    }
+    let rcode = BigEndian::read_u16(&buf[2..4]) & 0x0f;
+    if rcode == RCODE_NXDOMAIN {
+        return Err(UrlErr);
+    }
+    if rcode != 0 {
+        return Err(NetErr);
+    }
+    let ancount = BigEndian::read_u16(&buf[6..8]);
+    let mut pos = next + 4;
+    let mut answer:Answer = Default::default();
+    for _ in 0..ancount {
+        let (owner, next) = read_name(buf, pos)?;
+        if next + 10 > buf.len() {
+            return Err(NetErr);
+        }
+        let rtype = BigEndian::read_u16(&buf[next..]);
+        let ttl = BigEndian::read_u32(&buf[next + 4..]);
+        let rdlen = BigEndian::read_u16(&buf[next + 8..]) as usize;
+        let rdata = next + 10;
+        if rdata + rdlen > buf.len() {
+            return Err(NetErr);
+        }
+        pos = rdata + rdlen;
+        match (rtype, rdlen) {
+            (TYPE_A, 4) => {
+                let ip = IpAddr::V4(Ipv4Addr::new(buf[rdata], buf[rdata + 1], buf[rdata + 2], buf[rdata + 3]));
+                answer.addrs.entry(owner).or_insert_with(Vec::new).push(ip);
+            },
+            (TYPE_AAAA, 16) => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&buf[rdata..rdata + 16]);
+                answer.addrs.entry(owner).or_insert_with(Vec::new).push(IpAddr::V6(Ipv6Addr::from(octets)));
+            },
+            (TYPE_CNAME, _) => {
+                let (cname, _) = read_name(buf, rdata)?;
+                let _ = answer.cnames.insert(owner, cname);
+            },
+            _ => continue,
+        }
+        answer.ttl = min_ttl(answer.ttl, Some(ttl));
+    }
+    Ok(answer)
+}
+
+///the name at pos in lower case and the position after it, the pointers are followed
+fn read_name(buf:&[u8], pos:usize) -> Result<(String, usize), ErrCode> {
+    let mut labels:Vec<String> = Vec::new();
+    let mut pos = pos;
+    let mut end = None;
+    let mut jumps = 0;
+    loop {
+        let len = *buf.get(pos).ok_or(NetErr)? as usize;
+        if len & 0xc0 == 0xc0 {
+            let low = *buf.get(pos + 1).ok_or(NetErr)? as usize;
+            if end.is_none() {
+                end = Some(pos + 2);
+            }
+            jumps += 1;
+            if jumps > 64 {
+                return Err(NetErr);
+            }
+            pos = ((len & 0x3f) << 8) | low;
+            continue;
+        }
+        if len == 0 {
+            let end = end.unwrap_or(pos + 1);
+            return Ok((labels.join(".").to_lowercase(), end));
+        }
+        let label = buf.get(pos + 1..pos + 1 + len).ok_or(NetErr)?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        pos += 1 + len;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///a response to the query with the records appended, each is the owner,
+    ///the type, the ttl and the rdata
+    fn response(query:&[u8], rcode:u8, records:&[(&[u8], u16, u32, Vec<u8>)]) -> Vec<u8> {
+        let mut buf = query.to_vec();
+        buf[2] = 0x81;
+        buf[3] = 0x80 | rcode;
+        BigEndian::write_u16(&mut buf[6..8], records.len() as u16);
+        for &(owner, rtype, ttl, ref rdata) in records.iter() {
+            buf.extend_from_slice(owner);
+            let mut fixed = [0u8; 10];
+            BigEndian::write_u16(&mut fixed[0..2], rtype);
+            BigEndian::write_u16(&mut fixed[2..4], CLASS_IN);
+            BigEndian::write_u32(&mut fixed[4..8], ttl);
+            BigEndian::write_u16(&mut fixed[8..10], rdata.len() as u16);
+            buf.extend_from_slice(&fixed);
+            buf.extend_from_slice(rdata);
+        }
+        buf
+    }
+
+    const QNAME:&[u8] = &[0xc0, 12]; //a pointer to the question
+
+    #[test]
+    fn build_query_layout() {
+        let query = build_query(0x1234, "www.Example.com", TYPE_A).unwrap();
+        assert_eq!(&query[..12], &[0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&query[12..], b"\x03www\x07Example\x03com\x00\x00\x01\x00\x01");
+        assert_eq!(build_query(1, "a..b", TYPE_A), Err(UrlErr));
+        assert_eq!(build_query(1, &"a".repeat(64), TYPE_A), Err(UrlErr));
+    }
+
+    #[test]
+    fn parse_addresses() {
+        let query = build_query(7, "example.com", TYPE_A).unwrap();
+        let buf = response(&query, 0, &[
+            (QNAME, TYPE_A, 300, vec![93, 184, 216, 34]),
+            (QNAME, TYPE_A, 60, vec![93, 184, 216, 35]),
+        ]);
+        let answer = parse_response(&buf, 7, "example.com.", TYPE_A).unwrap();
+        assert_eq!(answer.addrs["example.com"], vec![
+            "93.184.216.34".parse::<IpAddr>().unwrap(),
+            "93.184.216.35".parse::<IpAddr>().unwrap(),
+        ]);
+        assert_eq!(answer.ttl, Some(60));
+
+        let query = build_query(8, "example.com", TYPE_AAAA).unwrap();
+        let ip:Ipv6Addr = "2606:2800:220:1::248".parse().unwrap();
+        let buf = response(&query, 0, &[(QNAME, TYPE_AAAA, 10, ip.octets().to_vec())]);
+        let answer = parse_response(&buf, 8, "example.com", TYPE_AAAA).unwrap();
+        assert_eq!(answer.addrs["example.com"], vec![IpAddr::V6(ip)]);
+    }
+
+    #[test]
+    fn parse_cname_chain() {
+        let query = build_query(9, "www.example.com", TYPE_A).unwrap();
+        let target = b"\x04edge\x07example\x03net\x00".to_vec();
+        //the owner of the A record points into the rdata of the cname
+        let offset = query.len() + 2 + 10;
+        let buf = response(&query, 0, &[
+            (QNAME, TYPE_CNAME, 300, target),
+            (&[0xc0, offset as u8], TYPE_A, 20, vec![10, 0, 0, 1]),
+        ]);
+        let answer = parse_response(&buf, 9, "www.example.com", TYPE_A).unwrap();
+        assert_eq!(answer.cnames["www.example.com"], "edge.example.net");
+        assert_eq!(answer.addrs["edge.example.net"], vec!["10.0.0.1".parse::<IpAddr>().unwrap()]);
+        assert_eq!(answer.ttl, Some(20));
+    }
+
+    #[test]
+    fn parse_failures() {
+        let query = build_query(10, "example.com", TYPE_A).unwrap();
+        let ok = response(&query, 0, &[(QNAME, TYPE_A, 300, vec![1, 2, 3, 4])]);
+        assert!(parse_response(&ok, 10, "example.com", TYPE_A).is_ok());
+        //nxdomain, servfail, another id, the query itself
+        assert_eq!(parse_response(&response(&query, 3, &[]), 10, "example.com", TYPE_A).err(), Some(UrlErr));
+        assert_eq!(parse_response(&response(&query, 2, &[]), 10, "example.com", TYPE_A).err(), Some(NetErr));
+        assert_eq!(parse_response(&ok, 11, "example.com", TYPE_A).err(), Some(NetErr));
+        assert_eq!(parse_response(&query, 10, "example.com", TYPE_A).err(), Some(NetErr));
+        //the answer of another question
+        assert_eq!(parse_response(&ok, 10, "example.org", TYPE_A).err(), Some(NetErr));
+        assert_eq!(parse_response(&ok, 10, "example.com", TYPE_AAAA).err(), Some(NetErr));
+        //a record past the end
+        assert_eq!(parse_response(&ok[..ok.len() - 2], 10, "example.com", TYPE_A).err(), Some(NetErr));
+    }
+
+    #[test]
+    fn question_must_match() {
+        let query = build_query(12, "example.com", TYPE_A).unwrap();
+        let ok = response(&query, 0, &[]);
+        assert!(same_question(&ok, &query));
+        let upper = response(&build_query(12, "EXAMPLE.com", TYPE_A).unwrap(), 0, &[]);
+        assert!(same_question(&upper, &query));
+        let other = response(&build_query(12, "example.org", TYPE_A).unwrap(), 0, &[]);
+        assert!(!same_question(&other, &query));
+        assert!(!same_question(&ok[..20], &query));
+    }
+
+    #[test]
+    fn name_pointer_loop() {
+        let mut buf = vec![0u8; 12];
+        buf.extend_from_slice(&[0xc0, 12]);
+        assert_eq!(read_name(&buf, 12).err(), Some(NetErr));
+        assert_eq!(read_name(&[3, b'a'], 0).err(), Some(NetErr));
+    }
+
+    ///answers the queries of every type with (rcode, delay in ms, ttl, rdata),
+    ///none leaves the query unanswered
+    fn nameserver(answer:fn(u16) -> Option<(u8, u64, u32, Vec<u8>)>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let _ = thread::spawn(move || {
+            let mut buf = vec![0u8; 512];
+            loop {
+                let (size, peer) = socket.recv_from(&mut buf).unwrap();
+                let query = buf[..size].to_vec();
+                let qtype = BigEndian::read_u16(&query[size - 4..size - 2]);
+                let (rcode, delay, ttl, rdata) = match answer(qtype) {
+                    Some(answer) => answer,
+                    None => continue,
+                };
+                let records:Vec<(&[u8], u16, u32, Vec<u8>)> = if rdata.is_empty() { Vec::new() } else { vec![(QNAME, qtype, ttl, rdata)] };
+                let buf = response(&query, rcode, &records);
+                let socket = socket.try_clone().unwrap();
+                let _ = thread::spawn(move || {
+                    thread::sleep(Duration::from_millis(delay));
+                    let _ = socket.send_to(&buf, peer);
+                });
+            }
+        });
+        addr
+    }
+
+    fn resolver(answer:fn(u16) -> Option<(u8, u64, u32, Vec<u8>)>, time_out:u64) -> Resolver {
+        Resolver::new(vec![nameserver(answer)], Duration::from_millis(time_out), 0)
+    }
+
+    const V4:[u8; 4] = [192, 0, 2, 1];
+    const V6:[u8; 16] = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+
+    fn ips() -> Vec<IpAddr> {
+        vec![IpAddr::from(V6), IpAddr::from(V4)]
+    }
+
+    #[test]
+    fn slow_aaaa_is_kept() {
+        let resolver = resolver(|qtype| match qtype {
+            TYPE_A => Some((0, 0, 300, V4.to_vec())),
+            _ => Some((0, 300, 120, V6.to_vec())),
+        }, 1000);
+        assert_eq!(resolver.resolve("example.com"), Ok((ips(), Some(120))));
+    }
+
+    #[test]
+    fn one_type_missing() {
+        //no AAAA records
+        let resolver = resolver(|qtype| match qtype {
+            TYPE_A => Some((0, 0, 300, V4.to_vec())),
+            _ => Some((0, 0, 300, Vec::new())),
+        }, 1000);
+        assert_eq!(resolver.resolve("example.com"), Ok((vec![IpAddr::from(V4)], Some(300))));
+        //a nxdomain of one type keeps the other, for a short while
+        let resolver = self::resolver(|qtype| match qtype {
+            TYPE_A => Some((0, 0, 300, V4.to_vec())),
+            _ => Some((3, 0, 300, Vec::new())),
+        }, 1000);
+        assert_eq!(resolver.resolve("example.com"), Ok((vec![IpAddr::from(V4)], Some(0))));
+        //so does an unanswered one
+        let resolver = self::resolver(|qtype| match qtype {
+            TYPE_AAAA => Some((0, 0, 300, V6.to_vec())),
+            _ => None,
+        }, 200);
+        assert_eq!(resolver.resolve("example.com"), Ok((vec![IpAddr::from(V6)], Some(0))));
+    }
+
+    #[test]
+    fn failures() {
+        let resolver = resolver(|_| Some((3, 0, 0, Vec::new())), 1000);
+        assert_eq!(resolver.resolve("nx.test"), Err(UrlErr));
+        let resolver = self::resolver(|_| None, 100);
+        assert_eq!(resolver.resolve("example.com"), Err(NetErr));
+        //no records of either type
+        let resolver = self::resolver(|_| Some((0, 0, 0, Vec::new())), 1000);
+        assert_eq!(resolver.resolve("example.com"), Err(UrlErr));
+        assert_eq!(resolver.resolve("[::1]"), Ok((vec!["::1".parse().unwrap()], None)));
+    }
+}
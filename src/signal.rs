@@ -0,0 +1,99 @@
+//! process signals, the handlers set flags and wake the accept loops through
+//! a pipe, the loops block in wait until a connection or a signal arrives.
+
+use std::io;
+use std::io::ErrorKind;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::thread;
+use std::time::Duration;
+
+extern crate libc;
+
+static TERMINATE: AtomicBool = AtomicBool::new(false);
+static RELOAD: AtomicBool = AtomicBool::new(false);
+static WAKE_READ: AtomicI32 = AtomicI32::new(-1);
+static WAKE_WRITE: AtomicI32 = AtomicI32::new(-1);
+
+///why the accept loop returned
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum Event {
+    Terminate, //the tunnels are drained, the process should exit
+    Reload, //the config should be reloaded, then start again
+}
+
+extern "C" fn on_terminate(_sig:libc::c_int) {
+    TERMINATE.store(true, Ordering::SeqCst);
+    wake();
+}
+
+extern "C" fn on_reload(_sig:libc::c_int) {
+    RELOAD.store(true, Ordering::SeqCst);
+    wake();
+}
+
+///install the handlers of SIGTERM, SIGINT and SIGHUP and the pipe which wakes the accept loops
+pub fn init() {
+    unsafe {
+        let mut fds = [0 as libc::c_int; 2];
+        if WAKE_READ.load(Ordering::SeqCst) < 0 && libc::pipe(fds.as_mut_ptr()) == 0 {
+            for &fd in fds.iter() {
+                let flags = libc::fcntl(fd, libc::F_GETFL);
+                let _ = libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
+                let _ = libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+            }
+            WAKE_READ.store(fds[0], Ordering::SeqCst);
+            WAKE_WRITE.store(fds[1], Ordering::SeqCst);
+        }
+        libc::signal(libc::SIGTERM, on_terminate as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        libc::signal(libc::SIGINT, on_terminate as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        libc::signal(libc::SIGHUP, on_reload as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+}
+
+pub fn is_terminated() -> bool {
+    TERMINATE.load(Ordering::SeqCst)
+}
+
+///reload the config, as if SIGHUP was received
+pub fn reload() {
+    RELOAD.store(true, Ordering::SeqCst);
+    wake();
+}
+
+///true once for every reload request
+pub fn take_reload() -> bool {
+    RELOAD.swap(false, Ordering::SeqCst)
+}
+
+///return from wait, safe in a signal handler
+pub fn wake() {
+    let fd = WAKE_WRITE.load(Ordering::SeqCst);
+    if fd >= 0 {
+        unsafe {
+            let _ = libc::write(fd, b"w".as_ptr() as *const libc::c_void, 1);
+        }
+    }
+}
+
+///block until a listener has a connection or wake is called, the ready listeners are returned
+pub fn wait(listeners:&[RawFd]) -> Vec<RawFd> {
+    let wake_fd = WAKE_READ.load(Ordering::SeqCst);
+    let mut fds:Vec<libc::pollfd> = listeners.iter().chain(Some(&wake_fd)).map(|&fd| {
+        libc::pollfd {fd: fd, events: libc::POLLIN, revents: 0}
+    }).collect();
+    let rst = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+    if rst < 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() != ErrorKind::Interrupted {
+            error!("{}", e);
+            thread::sleep(Duration::from_millis(100));
+        }
+        return Vec::new();
+    }
+    if fds[listeners.len()].revents != 0 {
+        let mut buf = [0u8; 64];
+        while unsafe { libc::read(wake_fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) } > 0 {}
+    }
+    fds[..listeners.len()].iter().filter(|fd| fd.revents != 0).map(|fd| fd.fd).collect()
+}
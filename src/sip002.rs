@@ -0,0 +1,210 @@
+//! SIP002 server urls:
+//! ss://websafe-base64(method:password)@host:port/?plugin=name%3Bopts#tag
+
+use define::ErrCode;
+use define::ErrCode::*;
+
+use config::{ServerConfig, METHODS};
+use helper;
+use helper::base64;
+
+pub fn parse(url:&str) -> Result<ServerConfig, ErrCode> {
+    let rest = url.trim();
+    if !rest.starts_with("ss://") {
+        return Err(UrlErr);
+    }
+    let rest = &rest[5..];
+    let (rest, tag) = match rest.find('#') {
+        Some(pos) => (&rest[..pos], Some(percent_decode(&rest[pos + 1..])?)),
+        None => (rest, None),
+    };
+    let (rest, query) = match rest.find('?') {
+        Some(pos) => (&rest[..pos], Some(&rest[pos + 1..])),
+        None => (rest, None),
+    };
+    let rest = rest.trim_end_matches('/');
+    let at = rest.rfind('@').ok_or(UrlErr)?;
+    let (method, password) = parse_userinfo(&rest[..at])?;
+    let (server, server_port) = parse_host(&rest[at + 1..])?;
+
+    let mut plugin = None;
+    let mut plugin_opts = None;
+    if let Some(query) = query {
+        for pair in query.split('&') {
+            let mut kv = pair.splitn(2, '=');
+            let key = kv.next().unwrap_or("");
+            let value = percent_decode(kv.next().unwrap_or(""))?;
+            if key == "plugin" && value.len() > 0 {
+                let mut parts = value.splitn(2, ';');
+                plugin = parts.next().map(|s| s.to_string());
+                plugin_opts = parts.next().map(|s| s.to_string());
+            }
+        }
+    }
+    Ok(ServerConfig {
+        server: server,
+        server_port: server_port,
+        password: password,
+        method: method,
+        plugin: plugin,
+        plugin_opts: plugin_opts,
+        remarks: tag,
+        acl: None,
+    })
+}
+
+///an unspecified listen address like 0.0.0.0 is replaced by the host name
+pub fn generate(server:&ServerConfig) -> String {
+    let userinfo = format!("{}:{}", server.method, server.password);
+    let mut host = server.server.clone();
+    if host.is_empty() || host == "0.0.0.0" || host == "::" {
+        host = helper::hostname().unwrap_or(host);
+    }
+    if host.contains(':') {
+        host = format!("[{}]", host);
+    }
+    let mut url = format!("ss://{}@{}:{}", base64::encode(userinfo.as_bytes(), true, false), host, server.server_port);
+    if let Some(ref plugin) = server.plugin {
+        let value = match server.plugin_opts {
+            Some(ref opts) => format!("{};{}", plugin, opts),
+            None => plugin.clone(),
+        };
+        url.push_str(&format!("/?plugin={}", percent_encode(&value)));
+    }
+    if let Some(ref remarks) = server.remarks {
+        url.push_str(&format!("#{}", percent_encode(remarks)));
+    }
+    url
+}
+
+///base64 of method:password, or the percent encoded plain text
+fn parse_userinfo(userinfo:&str) -> Result<(String, String), ErrCode> {
+    let plain = percent_decode(userinfo)?;
+    let text = if plain.contains(':') {
+        plain
+    } else {
+        String::from_utf8(base64::decode(&plain)?).or(Err(UrlErr))?
+    };
+    let pos = text.find(':').ok_or(UrlErr)?;
+    let method = text[..pos].to_lowercase();
+    if !METHODS.contains(&method.as_str()) {
+        return Err(UrlErr);
+    }
+    Ok((method, text[pos + 1..].to_string()))
+}
+
+///host:port, the ipv6 host is in brackets
+fn parse_host(host_port:&str) -> Result<(String, u32), ErrCode> {
+    let pos = host_port.rfind(':').ok_or(UrlErr)?;
+    let host = &host_port[..pos];
+    let port:u32 = host_port[pos + 1..].parse().or(Err(UrlErr))?;
+    if port == 0 || port > 65535 {
+        return Err(UrlErr);
+    }
+    let host = if host.starts_with('[') && host.ends_with(']') {
+        &host[1..host.len() - 1]
+    } else {
+        host
+    };
+    if host.is_empty() {
+        return Err(UrlErr);
+    }
+    Ok((host.to_string(), port))
+}
+
+fn percent_encode(input:&str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for b in input.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => out.push(b as char),
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+fn percent_decode(input:&str) -> Result<String, ErrCode> {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            if i + 2 >= bytes.len() {
+                return Err(UrlErr);
+            }
+            let hex = ::std::str::from_utf8(&bytes[i + 1..i + 3]).or(Err(UrlErr))?;
+            out.push(u8::from_str_radix(hex, 16).or(Err(UrlErr))?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).or(Err(UrlErr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server() -> ServerConfig {
+        ServerConfig {
+            server: "192.168.100.1".to_string(),
+            server_port: 8888,
+            password: "pass:word@1".to_string(),
+            method: "plain".to_string(),
+            plugin: Some("obfs-local".to_string()),
+            plugin_opts: Some("obfs=http;obfs-host=example.com".to_string()),
+            remarks: Some("my server".to_string()),
+            acl: None,
+        }
+    }
+
+    #[test]
+    fn generate_then_parse() {
+        let url = generate(&server());
+        assert_eq!(url, "ss://cGxhaW46cGFzczp3b3JkQDE@192.168.100.1:8888/?plugin=obfs-local%3Bobfs%3Dhttp%3Bobfs-host%3Dexample.com#my%20server");
+        let parsed = parse(&url).unwrap();
+        assert_eq!(parsed.server, "192.168.100.1");
+        assert_eq!(parsed.server_port, 8888);
+        assert_eq!(parsed.method, "plain");
+        assert_eq!(parsed.password, "pass:word@1");
+        assert_eq!(parsed.plugin, Some("obfs-local".to_string()));
+        assert_eq!(parsed.plugin_opts, Some("obfs=http;obfs-host=example.com".to_string()));
+        assert_eq!(parsed.remarks, Some("my server".to_string()));
+    }
+
+    #[test]
+    fn ipv6_host_in_brackets() {
+        let mut cfg = server();
+        cfg.server = "2001:db8::1".to_string();
+        cfg.plugin = None;
+        cfg.remarks = None;
+        let url = generate(&cfg);
+        assert!(url.ends_with("@[2001:db8::1]:8888"));
+        let parsed = parse(&url).unwrap();
+        assert_eq!(parsed.server, "2001:db8::1");
+        assert_eq!(parsed.plugin, None);
+    }
+
+    #[test]
+    fn plain_and_padded_userinfo() {
+        let parsed = parse("ss://none:secret@example.com:443#tag").unwrap();
+        assert_eq!((parsed.method.as_str(), parsed.password.as_str()), ("none", "secret"));
+        let parsed = parse("ss://bm9uZTpzZWNyZXQ=@example.com:443/").unwrap();
+        assert_eq!((parsed.method.as_str(), parsed.password.as_str()), ("none", "secret"));
+        assert_eq!(parsed.remarks, None);
+    }
+
+    #[test]
+    fn invalid_urls() {
+        assert!(parse("http://none:x@example.com:443").is_err());
+        assert!(parse("ss://none:x@example.com").is_err());
+        assert!(parse("ss://none:x@example.com:0").is_err());
+        assert!(parse("ss://none:x@example.com:65536").is_err());
+        assert!(parse("ss://none:x@:443").is_err());
+        assert!(parse("ss://none:x@example.com:443#%zz").is_err());
+        //the ciphers the tunnel does not implement
+        assert!(parse("ss://YWVzLTI1Ni1nY206c2VjcmV0@example.com:443").is_err());
+    }
+}